@@ -0,0 +1,214 @@
+// Measures how the upload window affects throughput over a high latency connection.
+// Every write to the fake host waits for a simulated round trip before it is acknowledged, as it would over a
+// Teleport proxy. The upload loop the engine replaced is measured first: a blocking thread that waits for every
+// 64 KiB write through `Handle::block_on`. A window of 1 waits for every write as well, but on the async engine.
+//
+//     cargo bench --bench upload
+
+use drone_teleport::{
+    config::rollout::Cancellation,
+    transport::{FileSystem, LocalTransport, Process, RemoteFile, RemoteSource, Transport},
+    Silent, TransferEngine,
+};
+use futures::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Handle;
+
+// The size of the archive that is uploaded
+const SIZE: usize = 32 << 20;
+
+// The simulated round trip of a single write
+const LATENCY: Duration = Duration::from_millis(20);
+
+// The size of every write of the replaced upload loop
+const BLOCKING_WRITE: usize = 64 * 1024;
+
+/// A file on a host that acknowledges every write one round trip after it was sent
+struct SlowFile<'a> {
+    inner: Box<dyn RemoteFile<'a> + 'a>,
+}
+
+impl<'a> RemoteFile<'a> for SlowFile<'a> {
+    fn write_at<'b>(&'b self, offset: u64, buf: &'b [u8]) -> BoxFuture<'b, io::Result<()>> {
+        Box::pin(async move {
+            tokio::time::sleep(LATENCY).await;
+            self.inner.write_at(offset, buf).await
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.close()
+    }
+}
+
+/// The file system of a host behind a slow connection
+struct SlowFileSystem<'a> {
+    inner: Box<dyn FileSystem<'a> + 'a>,
+}
+
+impl<'a> FileSystem<'a> for SlowFileSystem<'a> {
+    fn create_dir<'b>(&'b self, path: &'b str) -> BoxFuture<'b, io::Result<()>> {
+        self.inner.create_dir(path)
+    }
+
+    fn create<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteFile<'b> + 'b>>> {
+        Box::pin(async move {
+            let inner = self.inner.create(path).await?;
+            Ok(Box::new(SlowFile { inner }) as Box<dyn RemoteFile<'b> + 'b>)
+        })
+    }
+
+    fn open<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteSource<'b> + 'b>>> {
+        self.inner.open(path)
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.close()
+    }
+}
+
+/// A host faked on the local machine, behind a slow connection
+struct SlowTransport(LocalTransport);
+
+impl Transport for SlowTransport {
+    fn spawn<'a>(
+        &'a self,
+        command: String,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Process<'a> + 'a>>> {
+        self.0.spawn(command)
+    }
+
+    fn files<'a>(&'a self) -> BoxFuture<'a, io::Result<Box<dyn FileSystem<'a> + 'a>>> {
+        Box::pin(async move {
+            let inner = self.0.files().await?;
+            Ok(Box::new(SlowFileSystem { inner }) as Box<dyn FileSystem<'a> + 'a>)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Transport::close(Box::new(self.0))
+    }
+}
+
+// The upload loop the engine replaced: on a blocking thread, the archive is read in 64 KiB chunks, and every write
+// is awaited through `Handle::block_on` before the next chunk is read. The archive is then extracted like the engine
+// does. Returns the number of bytes uploaded.
+fn blocking_upload(transport: &SlowTransport, archive: &str) -> io::Result<u64> {
+    let handle = Handle::current();
+    let files = handle.block_on(transport.files())?;
+    handle.block_on(files.create_dir("app"))?;
+    let file = handle.block_on(files.create("app/bundle.tar"))?;
+
+    let mut local = std::fs::File::open(archive)?;
+    let mut buffer = vec![0u8; BLOCKING_WRITE];
+    let mut uploaded = 0;
+    loop {
+        let read = local.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        handle.block_on(file.write_at(uploaded, &buffer[..read]))?;
+        uploaded += read as u64;
+    }
+    handle.block_on(file.close())?;
+    handle.block_on(files.close())?;
+
+    let extracted =
+        handle.block_on(transport.output(String::from("tar -xf app/bundle.tar -C app")))?;
+    assert!(extracted.status.success());
+
+    Ok(uploaded)
+}
+
+// Prints the time and throughput of an upload of `bytes`
+fn print_result(label: &str, bytes: u64, elapsed: Duration) {
+    println!(
+        "{:<9}: {:>6.2} s, {:>7.2} MiB/s",
+        label,
+        elapsed.as_secs_f64(),
+        bytes as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    // Random data doesn't compress, so compression is left out of the measurement
+    let local = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let relative = local
+        .path()
+        .strip_prefix(std::env::current_dir().unwrap())
+        .unwrap();
+    let mut seed: u32 = 7;
+    let bundle: Vec<u8> = (0..SIZE)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    std::fs::write(local.path().join("bundle.bin"), &bundle).unwrap();
+
+    let src = format!("{}/*", relative.display());
+    let files = HashMap::from([(src.clone(), String::from("app"))]);
+
+    println!(
+        "Uploading {} MiB with {} ms of latency per write",
+        SIZE >> 20,
+        LATENCY.as_millis()
+    );
+    {
+        let mut engine = TransferEngine::new();
+        engine.compress(false);
+        let archives = engine.prepare_all([&src], &Silent);
+        let archive = archives[&src].as_ref().unwrap().path().to_string();
+
+        let root = tempfile::tempdir().unwrap();
+        let transport = SlowTransport(LocalTransport::new(root.path()));
+
+        let started = Instant::now();
+        let uploaded = tokio::task::spawn_blocking(move || blocking_upload(&transport, &archive))
+            .await
+            .unwrap()
+            .unwrap();
+        print_result("old loop", uploaded, started.elapsed());
+        drop(archives);
+    }
+
+    for window in [1, 4, 16, 64] {
+        let mut engine = TransferEngine::new();
+        engine.compress(false).window(window);
+        let archives = engine.prepare_all([&src], &Silent);
+
+        let root = tempfile::tempdir().unwrap();
+        let transport = SlowTransport(LocalTransport::new(root.path()));
+        let mut steps = Vec::new();
+
+        let started = Instant::now();
+        engine
+            .transfer(
+                &transport,
+                "bench",
+                &files,
+                &archives,
+                &Cancellation::default(),
+                &Silent,
+                &mut steps,
+            )
+            .await
+            .unwrap();
+        print_result(
+            &format!("window {:>2}", window),
+            steps[0].bytes.unwrap(),
+            started.elapsed(),
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use base64::{engine::general_purpose, Engine as _};
+use colored::Colorize;
+use human_bytes::human_bytes;
+use serde_json::{json, Value};
+use std::io::Write;
+
+use crate::config::{
+    report::{self, HostResult, HostStatus},
+    state::Config,
+};
+
+// The adaptive card template Drone renders the card data with
+const CARD_SCHEMA: &str =
+    "https://raw.githubusercontent.com/charlesportwoodii/drone-teleport/master/card.json";
+
+// Builds the data for the card template in card.json
+pub fn build_card(cfg: &Config, results: &[HostResult], error: Option<&str>) -> Value {
+    let count = |status: HostStatus| {
+        results
+            .iter()
+            .filter(|result| result.status == status)
+            .count()
+    };
+    let status = match error.is_none() && report::exit_code(results) == 0 {
+        true => "succeeded",
+        false => "failed",
+    };
+
+    let hosts: Vec<Value> = results
+        .iter()
+        .map(|result| {
+            let bytes: u64 = result.steps.iter().filter_map(|step| step.bytes).sum();
+            json!({
+                "host": result.host,
+                "status": result.status.as_str(),
+                "failure": report::describe_failure(result),
+                "duration": format!("{:.1}s", result.duration.as_secs_f64()),
+                "transferred": match bytes {
+                    0 => String::new(),
+                    bytes => human_bytes(bytes as f64),
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "operation": cfg.get_operation(),
+        "status": status,
+        "error": error.unwrap_or_default(),
+        "total": results.len(),
+        "succeeded": count(HostStatus::Succeeded),
+        "failed": count(HostStatus::Failed),
+        "cancelled": count(HostStatus::Cancelled),
+        "skipped": count(HostStatus::Skipped),
+        "duration": format!("{:.1}s", cfg.started.elapsed().unwrap_or_default().as_secs_f64()),
+        "hosts": hosts,
+    })
+}
+
+// Writes the card to DRONE_CARD_PATH, when Drone set it for the step
+pub fn write_card(cfg: &Config, results: &[HostResult], error: Option<&str>) {
+    let path = match std::env::var("DRONE_CARD_PATH") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+
+    let card = json!({
+        "schema": CARD_SCHEMA,
+        "data": build_card(cfg, results, error),
+    })
+    .to_string();
+
+    // Drone reads cards written to stdout from an escape sequence, the same way its own plugins write them
+    let written = match path.as_str() {
+        "/dev/stdout" => {
+            let mut stdout = std::io::stdout();
+            write!(
+                stdout,
+                "\u{1b}]1338;{}\u{1b}]0m",
+                general_purpose::STANDARD.encode(card)
+            )
+            .and_then(|_| stdout.flush())
+        }
+        path => std::fs::write(path, card),
+    };
+
+    if let Err(error) = written {
+        println!(
+            "{} {}: {}",
+            "Unable to write the Drone card to".red().bold(),
+            path,
+            error
+        );
+    }
+}
@@ -1,9 +1,23 @@
 use clap::Parser;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
 
-use crate::config::state::Config;
-use colored::Colorize;
-use std::{process::exit, sync::Arc};
+use crate::{
+    config::{
+        console::Console,
+        junit, plan,
+        report::{self, HostResult},
+        rollout::{self, Cancellation},
+        shell,
+        state::{self, Config},
+    },
+    error::Error,
+    runner::{CommandRunner, ScriptMode},
+    transport::Connector,
+};
 
 #[derive(Debug, Parser, Clone)]
 #[clap(author, version, about, long_about = None)]
@@ -15,23 +29,104 @@ pub struct ConnectConfig {
     /// The script to execute on the given targets
     #[clap(short, long, env = "PLUGIN_SCRIPT")]
     pub script: Vec<String>,
+
+    /// A list of environment variables from the Drone runner to export on the remote, e.g. DRONE_COMMIT_SHA. A trailing * matches any suffix (DRONE_*).
+    #[clap(
+        long,
+        value_parser,
+        required = false,
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        env = "PLUGIN_ENV_PASSTHROUGH"
+    )]
+    pub env_passthrough: Vec<String>,
+
+    /// How to run the script: as a single script in one shell, or each command in its own shell
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ScriptMode::Script,
+        env = "PLUGIN_SCRIPT_MODE"
+    )]
+    pub script_mode: ScriptMode,
+
+    /// The shell, with options, that runs the script when script_mode is script
+    #[clap(long, value_parser, default_value = "sh -e", env = "PLUGIN_SHELL")]
+    pub shell: String,
+
+    /// The directory on the remote to run commands from
+    #[clap(long, value_parser, env = "PLUGIN_WORKING_DIR")]
+    pub working_dir: Option<String>,
+
+    /// The user to run commands as, through non-interactive sudo
+    #[clap(long, value_parser, env = "PLUGIN_RUN_AS")]
+    pub run_as: Option<String>,
+
+    /// A file to write JUnit XML to, with every host as a testsuite and every command as a testcase
+    #[clap(long, value_parser, env = "PLUGIN_JUNIT_PATH")]
+    pub junit_path: Option<String>,
 }
 
 impl ConnectConfig {
-    // Helper function to return KEY=VAL environment variables to append to each command
-    pub fn build_env<'a>(&'a self) -> String {
-        let mut envstr: Vec<String> = Vec::new();
-        for (k, v) in &self.env {
-            envstr.push(format!("export {}={}", k, v));
+    // Whether a runner environment variable was requested through env_passthrough
+    fn is_passthrough(&self, name: &str) -> bool {
+        self.env_passthrough.iter().any(|pattern| {
+            let pattern = pattern.trim();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        })
+    }
+
+    // Collects the environment variables to export on the remote. Later sources take precedence:
+    // variables passed through from the runner, custom plugin settings (PLUGIN_FOO => FOO), then settings:env.
+    pub fn get_env_vars(&self, cfg: &Config) -> Result<BTreeMap<String, String>, Error> {
+        let mut vars: BTreeMap<String, String> = BTreeMap::new();
+
+        for (k, v) in &cfg.runner_env {
+            if !k.starts_with("PLUGIN_") && self.is_passthrough(k) {
+                vars.insert(k.to_string(), v.to_string());
+            }
         }
 
-        for (k, v) in std::env::vars() {
-            if k.starts_with("PLUGIN_") {
-                envstr.push(format!("export {}={}", k.replace("PLUGIN_", ""), v));
+        // The plugin's own settings (PLUGIN_SCRIPT, PLUGIN_DATA_PATH, ...) are never exported
+        let settings = state::get_setting_envs();
+        for (k, v) in &cfg.runner_env {
+            if let Some(name) = k.strip_prefix("PLUGIN_") {
+                if !settings.contains(k) {
+                    vars.insert(name.to_string(), v.to_string());
+                }
             }
         }
 
-        return envstr.join(" && ");
+        for (k, v) in &self.env {
+            vars.insert(k.to_string(), v.to_string());
+        }
+
+        if let Some(invalid) = vars.keys().find(|k| !shell::is_valid_name(k)) {
+            return Err(Error::Config(format!(
+                "Invalid environment variable name `{}`. Names may only contain letters, digits and underscores, and may not start with a digit.",
+                invalid
+            )));
+        }
+
+        Ok(vars)
+    }
+
+    // Builds the runner that executes the script on every host
+    pub fn get_runner(&self, cfg: &Config) -> Result<CommandRunner, Error> {
+        let mut runner = CommandRunner::new();
+        runner
+            .env(&self.get_env_vars(cfg)?)
+            .mode(self.script_mode)
+            .shell(&self.shell)
+            .working_dir(self.working_dir.as_deref())
+            .run_as(self.run_as.as_deref())
+            .timeout(cfg.get_command_timeout())
+            .capture(self.junit_path.is_some());
+
+        Ok(runner)
     }
 
     // Drone submits PLUGIN_SCRIPT as a comma-separated list if settings:script is used.
@@ -40,167 +135,173 @@ impl ConnectConfig {
     // @todo: Clap won't permit this function to be used with Vec<string> and parse it as Vec<string>
     // value_parser(parse_script_json) => Vec<String> results in:
     // thread 'main' panicked at 'Mismatch between definition and access of `script`. Could not downcast to alloc::string::String, need to downcast to alloc::vec::Vec<alloc::string::String>
-    pub fn parse_script_json<'a>(&'a self) -> Result<Vec<String>, std::io::Error> {
-        let script = self.script.clone();
-        if script.len() == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No commands to execute.",
-            ));
-        }
+    pub fn parse_script_json(&self) -> Result<Vec<String>, Error> {
+        let invalid =
+            |message: String| Error::Config(format!("Invalid settings:script: {}", message));
+        let script = match self.script.first() {
+            Some(script) => script,
+            None => return Err(Error::Config(String::from("No commands supplied."))),
+        };
 
         let mut hash: HashMap<String, serde_json::Value> =
-            serde_json::from_str(&script[0]).unwrap();
-
-        let key = String::from("commands");
-        if hash.contains_key(&key) {
-            let commands = hash.remove(&key).unwrap();
-            let mut ret: Vec<String> = Vec::new();
+            serde_json::from_str(script).map_err(|e| {
+                invalid(format!(
+                    "{}. Hint: use settings:script:commands, so the script is passed as JSON",
+                    e
+                ))
+            })?;
 
-            for command in commands.as_array().unwrap().clone() {
-                ret.push(serde_json::from_value(command).unwrap());
-            }
-
-            return Ok(ret);
+        match hash.remove("commands") {
+            Some(commands) => serde_json::from_value(commands)
+                .map_err(|e| invalid(format!("commands must be a list of strings: {}", e))),
+            None => Err(Error::Config(String::from(
+                "Missing settings:script:commands",
+            ))),
         }
-
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Missing settings:script:commands",
-        ));
     }
 
-    fn parse_env_json(
-        arg: &str,
-    ) -> Result<std::collections::HashMap<String, String>, std::io::Error> {
+    fn parse_env_json(arg: &str) -> Result<std::collections::HashMap<String, String>, Error> {
         // Parse
-        if arg == "" {
+        if arg.is_empty() {
             return Ok(HashMap::new());
         }
-        let v: HashMap<String, serde_json::Value> = serde_json::from_str(arg).unwrap();
+        let v: HashMap<String, serde_json::Value> = serde_json::from_str(arg).map_err(|e| {
+            Error::Config(format!(
+                "Invalid settings:env: {}. Hint: settings:env should be an object of names and values",
+                e
+            ))
+        })?;
 
         // Iterate over the serde_json::Value HashMap and convert properties into simple-to-use strings instead of raw values
         let mut n = HashMap::new();
         for (key, value) in v.into_iter() {
-            let parsed_val: String;
-            if value.is_string() {
-                parsed_val = serde_json::from_value(value).unwrap();
-            } else if value.is_boolean() || value.is_number() {
-                parsed_val = serde_json::to_string(&value).unwrap();
-            } else if value.is_null() {
-                parsed_val = String::from("");
-            } else {
-                // Ignore objects and arrays
-                parsed_val = String::from("");
-            }
+            let parsed_val: String = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                // Ignore nulls, objects and arrays
+                _ => String::from(""),
+            };
 
             n.insert(key.to_string(), parsed_val);
         }
 
-        return Ok(n);
+        Ok(n)
     }
 
-    // Connects to a remote SSH target and executes the requested commands
-    pub async fn connect<'a>(&'a self, cfg: &Config) {
-        // Store a lists of tasks so we can execute them asyncronously
-        let mut tasks = Vec::new();
-
-        // Iterate over each host and create the processing task
-        for host in cfg.hosts.to_owned() {
-            let sb = Arc::new(cfg.get_sb());
-            let env = Arc::new(self.build_env());
-            let commands = match self.parse_script_json() {
-                Ok(commands) => commands.to_owned(),
-                Err(_) => {
-                    println!("No commands supplied.");
-                    exit(1);
-                }
-            };
-            let debug = cfg.debug.to_owned();
-
-            let task = tokio::spawn(async move {
-                // Attempt to connect to the database via tsh
-                match sb.to_owned().connect(&host).await {
-                    Ok(session) => {
-                        // Iterate over all of the commands and run them syncronously
-                        for command in commands.iter() {
-                            let command_to_run = match env.trim().is_empty() {
-                                true => format!("{}", command),
-                                false => format!("{}; {}", env, command),
-                            };
-
-                            match session.shell(command_to_run).output().await {
-                                Ok(result) => {
-                                    println!(
-                                        "{}",
-                                        format!(
-                                            "{}: {}",
-                                            &host.to_owned().yellow(),
-                                            command.to_owned().green()
-                                        )
-                                    );
-                                    println!(
-                                        "{}{}",
-                                        String::from_utf8(result.stdout).unwrap(),
-                                        String::from_utf8(result.stderr).unwrap().red()
-                                    );
-
-                                    // If any commit exits with a non-0 exit status code, stop execution of this task.
-                                    if result.status.code() != Some(0) {
-                                        println!(
-                                            "{}",
-                                            format!("Exit: {}", result.status.code().unwrap())
-                                                .red()
-                                                .bold()
-                                        );
-                                        session.close().await.unwrap();
-                                        exit(1);
-                                    }
-                                }
-                                Err(error) => {
-                                    println!(
-                                        "{}",
-                                        format!(
-                                            "{}: {}",
-                                            &host.to_owned().yellow(),
-                                            command.to_owned().green()
-                                        )
-                                    );
-                                    // If a command fail (eg command not found or similar) stop processing additional commands
-                                    println!("{}\n", error.to_string().red().bold().italic());
-                                    session.close().await.unwrap();
-                                    exit(2);
-                                }
-                            };
-                        }
-                    }
-                    // Handle tsh connection errors
-                    Err(error) => {
-                        println!(
-                            "{} {}",
-                            "Unable to connect to Teleport target:".red().bold(),
-                            &host.to_owned().cyan().italic()
-                        );
-                        if debug {
-                            println!("\t{}", error.to_string().italic());
-                        }
-                        exit(3);
-                    }
-                };
-            });
-
-            // Push the task to the list
-            tasks.push(task);
+    // Connects to a single host and runs every command in order, stopping at the first failure
+    async fn run_host(
+        runner: Arc<CommandRunner>,
+        connector: Arc<dyn Connector>,
+        console: Console,
+        commands: Arc<Vec<String>>,
+        host: String,
+        cancellation: Cancellation,
+    ) -> HostResult {
+        let mut result = HostResult::new(&host);
+        let started = Instant::now();
+
+        // Attempt to connect to the database via tsh
+        let transport = match connector.connect(&host, &console).await {
+            Ok(transport) => transport,
+            // Handle tsh connection errors
+            Err(error) => {
+                console.failure(&host, &error);
+                result.fail_with(&error);
+                result.duration = started.elapsed();
+                return result;
+            }
+        };
+        result.connected = true;
+
+        let outcome = runner
+            .run(
+                transport.as_ref(),
+                &host,
+                &commands,
+                &cancellation,
+                &console,
+                &mut result.steps,
+            )
+            .await;
+
+        // Close the connection, errors don't matter
+        #[allow(unused_must_use)]
+        {
+            transport.close().await;
+        }
+
+        if let Err(error) = outcome {
+            console.failure(&host, &error);
+            result.fail_with(&error);
         }
 
-        // Execute all commands asyncronously
-        for task in tasks {
-            #[allow(unused_must_use)]
+        result.completed = result.steps.iter().filter(|step| !step.is_failed()).count();
+        result.duration = started.elapsed();
+        result
+    }
+
+    // Connects to a remote SSH target and executes the requested commands
+    pub async fn connect(&self, cfg: &Config) -> Result<i32, Error> {
+        self.connect_with(cfg, Arc::new(cfg.get_connection_builder()))
+            .await
+    }
+
+    // Executes the requested commands on every host, connecting to them with `connector`
+    pub async fn connect_with(
+        &self,
+        cfg: &Config,
+        connector: Arc<dyn Connector>,
+    ) -> Result<i32, Error> {
+        let commands = match self.parse_script_json() {
+            Ok(commands) => commands,
+            Err(error) => return Err(report::abort(cfg, error)),
+        };
+
+        // Render the commands for every host up front, so template errors are caught before connecting to anything
+        let mut rendered: HashMap<String, Arc<Vec<String>>> = HashMap::new();
+        for context in rollout::get_contexts(cfg) {
+            match commands
+                .iter()
+                .map(|command| context.render_command(command))
+                .collect::<Result<Vec<String>, Error>>()
             {
-                task.await;
+                Ok(commands) => {
+                    rendered.entry(context.host).or_insert(Arc::new(commands));
+                }
+                Err(error) => return Err(report::abort(cfg, error)),
             }
         }
 
-        exit(0);
+        let runner = match self.get_runner(cfg) {
+            Ok(runner) => Arc::new(runner),
+            Err(error) => return Err(report::abort(cfg, error)),
+        };
+
+        // Show what would run without connecting to any host
+        if cfg.dry_run {
+            let vars = self.get_env_vars(cfg).unwrap_or_default();
+            return Ok(plan::print_commands(cfg, &runner, &vars, &rendered));
+        }
+
+        let console = Console { debug: cfg.debug };
+
+        // Create the processing task for each host, and run them batch by batch
+        let results = rollout::run(cfg, |host, cancellation| {
+            tokio::spawn(ConnectConfig::run_host(
+                runner.clone(),
+                connector.clone(),
+                console,
+                rendered[&host].clone(),
+                host,
+                cancellation,
+            ))
+        })
+        .await;
+
+        if let Some(path) = &self.junit_path {
+            junit::write_junit(path, &results, &rendered);
+        }
+
+        Ok(report::finish(cfg, &results, "Commands"))
     }
 }
@@ -0,0 +1,186 @@
+use colored::Colorize;
+use human_bytes::human_bytes;
+
+use crate::{
+    error::Error,
+    output::{Event, Output},
+};
+
+/// Prints what happens on every host to the build log
+#[derive(Debug, Clone, Copy)]
+pub struct Console {
+    /// Whether the details only needed to debug a run are printed
+    pub debug: bool,
+}
+
+impl Console {
+    // Prints why a host failed
+    pub fn failure(&self, host: &str, error: &Error) {
+        if let Some(text) = self.format_failure(host, error) {
+            println!("{}", text);
+        }
+    }
+
+    // Formats why a host failed as it is printed, or None if nothing is printed
+    pub fn format_failure(&self, host: &str, error: &Error) -> Option<String> {
+        let text = match error {
+            Error::Connection(message) | Error::Auth(message) => {
+                let mut text = format!(
+                    "{} {}",
+                    "Unable to connect to Teleport target:".red().bold(),
+                    host.cyan().italic()
+                );
+                if self.debug || message.contains("timed out") {
+                    text.push_str(&format!("\n\t{}", message.italic()));
+                }
+                text
+            }
+            Error::CommandFailed { exit_code, .. } => format!("Exit: {}", exit_code.unwrap_or(-1))
+                .red()
+                .bold()
+                .to_string(),
+            Error::Timeout { command, seconds } => format!(
+                "{} {}: {} ({} seconds)",
+                "Command timed out on".red().bold(),
+                host.yellow(),
+                command.as_deref().unwrap_or_default().green(),
+                seconds
+            ),
+            Error::Cancelled => return None,
+            error => format!("{}: {}", host.yellow(), error.to_string().red().bold()),
+        };
+
+        Some(text)
+    }
+
+    // Formats an event as it is printed, or None if it isn't printed
+    pub fn format_event(&self, host: &str, event: &Event) -> Option<String> {
+        let text = match *event {
+            Event::Retrying {
+                error,
+                delay,
+                attempt,
+                retries,
+            } => format!(
+                "{}: {} {}, retrying in {:.1} seconds ({}/{})",
+                host.yellow(),
+                "Unable to connect:".red(),
+                error,
+                delay.as_secs_f64(),
+                attempt,
+                retries
+            ),
+            Event::Command(command) => format!("{}: {}", host.yellow(), command.green()),
+            Event::Line { line, is_stderr } => match is_stderr {
+                true => format!("{}: {}", host.yellow(), line.red()),
+                false => format!("{}: {}", host.yellow(), line),
+            },
+            Event::CreatingDirectory { src, dst } if self.debug => format!(
+                "{}: Ensuring remote directory path {} exists for {}",
+                host.bold().yellow(),
+                dst.italic().cyan(),
+                src.italic().cyan()
+            ),
+            Event::Archiving { src } if self.debug => format!(
+                "{}: Creating archive of {} to upload.",
+                host.bold().yellow(),
+                src.italic().cyan()
+            ),
+            Event::Compressing { src } => format!(
+                "{}: Compressing archive of {} prior to transfer.",
+                host.bold().yellow(),
+                src.italic().cyan()
+            ),
+            Event::Uploading { src, path, size } => format!(
+                "{}: Created remote file: {}\n{}: {} {} {}",
+                host.bold().yellow(),
+                path,
+                host.bold().yellow(),
+                "Transferring".bold(),
+                src.italic(),
+                match size {
+                    Some(size) => human_bytes(size as f64).bold().green(),
+                    None => "as it is archived".italic(),
+                }
+            ),
+            Event::Progress {
+                transferred, size, ..
+            } if self.debug => match size {
+                Some(size) => format!(
+                    "{}: {} {}/{} \r",
+                    host.bold().yellow(),
+                    "Transferring -".bold(),
+                    human_bytes(transferred as f64).bold().cyan(),
+                    human_bytes(size as f64).bold().green()
+                ),
+                None => format!(
+                    "{}: {} {} \r",
+                    host.bold().yellow(),
+                    "Transferring -".bold(),
+                    human_bytes(transferred as f64).bold().cyan()
+                ),
+            },
+            Event::Uploaded { src, size, elapsed } => format!(
+                "{}: {} {} {} in {} seconds",
+                host.bold().yellow(),
+                "Completed".bold(),
+                src.italic(),
+                human_bytes(size as f64).bold().green(),
+                elapsed.as_secs().to_string().bold().cyan()
+            ),
+            Event::Downloading { src, path, size } => format!(
+                "{}: {} {} from {} {}",
+                host.bold().yellow(),
+                "Downloading".bold(),
+                src.italic(),
+                path,
+                match size {
+                    Some(size) => human_bytes(size as f64).bold().green(),
+                    None => "".normal(),
+                }
+            ),
+            Event::Downloaded { src, size, elapsed } => format!(
+                "{}: {} {} {} in {} seconds",
+                host.bold().yellow(),
+                "Downloaded".bold(),
+                src.italic(),
+                human_bytes(size as f64).bold().green(),
+                elapsed.as_secs().to_string().bold().cyan()
+            ),
+            Event::Extracting { archive, dst } if self.debug => format!(
+                "{}: Extracting {} to {}",
+                host.bold().yellow(),
+                archive,
+                dst
+            ),
+            Event::Cleaning { archive } if self.debug => {
+                format!("{}: Deleting {} on remote", host.bold().yellow(), archive)
+            }
+            Event::Releasing { dst, name } => format!(
+                "{}: {} {}/current to {}",
+                host.bold().yellow(),
+                "Switching".bold(),
+                dst.italic(),
+                name.bold().green()
+            ),
+            Event::Pruning { dst, keep } if self.debug => format!(
+                "{}: Deleting all but the newest {} releases in {}",
+                host.bold().yellow(),
+                keep,
+                dst
+            ),
+            Event::Warning(message) => format!("{}: {}", host.yellow(), message.red().bold()),
+            _ => return None,
+        };
+
+        Some(text)
+    }
+}
+
+impl Output for Console {
+    fn event(&self, host: &str, event: Event) {
+        if let Some(text) = self.format_event(host, &event) {
+            println!("{}", text);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use clap::Parser;
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use crate::{
+    config::{
+        console::Console,
+        plan,
+        report::{self, HostResult},
+        rollout::{self, Cancellation},
+        state::Config,
+        transfer,
+    },
+    engine::TransferEngine,
+    error::Error,
+    transport::Connector,
+};
+
+#[derive(Debug, Parser, Clone)]
+#[clap(author, version, about, long_about = None)]
+pub struct FetchConfig {
+    /// A list of src => dst files to fetch, where src is a path or glob on the host, and dst a local directory
+    #[clap(short, long, env = "PLUGIN_FILES")]
+    pub files: Vec<String>,
+
+    /// Whether or not to compress the archive on the host before it is downloaded. Defaults to true.
+    #[clap(long, value_parser, default_value_t = true, env = "PLUGIN_COMPRESS")]
+    pub compress: bool,
+
+    /// ZSTD compression level.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 13,
+        env = "PLUGIN_COMPRESS_LEVEL"
+    )]
+    pub compress_level: i32,
+
+    /// How many SFTP reads may be in flight at the same time during a download. Defaults to 16.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 16,
+        env = "PLUGIN_DOWNLOAD_WINDOW"
+    )]
+    pub download_window: usize,
+}
+
+impl FetchConfig {
+    pub fn parse_files_json(&self) -> Result<std::collections::HashMap<String, String>, Error> {
+        transfer::parse_files(&self.files)
+    }
+
+    // Builds the engine that fetches the files from every host
+    pub fn get_engine(&self) -> TransferEngine {
+        let mut engine = TransferEngine::new();
+        engine
+            .compress(self.compress)
+            .compress_level(self.compress_level)
+            .window(self.download_window);
+
+        engine
+    }
+
+    // Connects to a single host over SFTP and fetches every file, stopping at the first failure
+    async fn fetch_host(
+        engine: Arc<TransferEngine>,
+        connector: Arc<dyn Connector>,
+        console: Console,
+        files: Arc<HashMap<String, String>>,
+        host: String,
+        cancellation: Cancellation,
+    ) -> HostResult {
+        let mut result = HostResult::new(&host);
+        let started = Instant::now();
+
+        let transport = match connector.connect(&host, &console).await {
+            Ok(transport) => transport,
+            Err(error) => {
+                // Failed to connect
+                console.failure(&host, &error);
+                result.fail_with(&error);
+                result.duration = started.elapsed();
+                return result;
+            }
+        };
+        result.connected = true;
+
+        let outcome = engine
+            .fetch(
+                transport.as_ref(),
+                &host,
+                &files,
+                &cancellation,
+                &console,
+                &mut result.steps,
+            )
+            .await;
+
+        // Close the connection, errors don't matter
+        #[allow(unused_must_use)]
+        {
+            transport.close().await;
+        }
+
+        if let Err(error) = outcome {
+            console.failure(&host, &error);
+            result.fail_with(&error);
+        }
+
+        result.completed = result.steps.iter().filter(|step| !step.is_failed()).count();
+        result.duration = started.elapsed();
+        result
+    }
+
+    // Fetches the requested files from the remote server
+    pub async fn fetch(&self, cfg: &Config) -> Result<i32, Error> {
+        self.fetch_with(cfg, Arc::new(cfg.get_connection_builder()))
+            .await
+    }
+
+    // Fetches the requested files from every host, connecting to them with `connector`
+    pub async fn fetch_with(
+        &self,
+        cfg: &Config,
+        connector: Arc<dyn Connector>,
+    ) -> Result<i32, Error> {
+        let files = match self.parse_files_json() {
+            Ok(files) => files,
+            Err(error) => return Err(report::abort(cfg, error)),
+        };
+
+        if files.is_empty() {
+            return Err(report::abort(cfg, Error::Config("File list missing src or dst. Hint: settings:files should be an array of objects with src & dst keypairs, not an individual array elements. (e.g.: files: { src: /var/log/app, dst: logs})".to_string())));
+        }
+
+        let rendered = transfer::render_files(cfg, &files)?;
+        let engine = Arc::new(self.get_engine());
+
+        // Show what would be fetched without connecting to any host
+        if cfg.dry_run {
+            return Ok(plan::print_fetches(cfg, &engine, &rendered));
+        }
+
+        let console = Console { debug: cfg.debug };
+
+        // Create the processing task for each host, and run them batch by batch
+        let results = rollout::run(cfg, |host, cancellation| {
+            tokio::spawn(FetchConfig::fetch_host(
+                engine.clone(),
+                connector.clone(),
+                console,
+                rendered[&host].clone(),
+                host,
+                cancellation,
+            ))
+        })
+        .await;
+
+        Ok(report::finish(cfg, &results, "Files"))
+    }
+}
@@ -0,0 +1,242 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The parts of an OpenSSH certificate that decide whether the Machine ID identity can be used
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    pub key_type: String,
+    pub key_id: String,
+    /// The users the certificate may log in as. An empty list permits any user.
+    pub principals: Vec<String>,
+    /// Seconds since the UNIX epoch the certificate is valid from
+    pub valid_after: u64,
+    /// Seconds since the UNIX epoch the certificate is valid until
+    pub valid_before: u64,
+    pub extensions: Vec<String>,
+}
+
+// Reads the fields of an OpenSSH certificate, in the order defined by PROTOCOL.certkeys
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], std::io::Error> {
+        if self.0.len() < len {
+            return Err(std::io::Error::other("certificate is truncated"));
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32, std::io::Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, std::io::Error> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], std::io::Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, std::io::Error> {
+        Ok(String::from_utf8_lossy(self.bytes()?).to_string())
+    }
+
+    // A list of strings packed into a single string, as used for principals
+    fn strings(&mut self) -> Result<Vec<String>, std::io::Error> {
+        let mut packed = Reader(self.bytes()?);
+        let mut strings = Vec::new();
+        while !packed.0.is_empty() {
+            strings.push(packed.string()?);
+        }
+
+        Ok(strings)
+    }
+
+    // Name/data pairs packed into a single string, as used for extensions. Only the names are kept.
+    fn names(&mut self) -> Result<Vec<String>, std::io::Error> {
+        let mut packed = Reader(self.bytes()?);
+        let mut names = Vec::new();
+        while !packed.0.is_empty() {
+            names.push(packed.string()?);
+            packed.bytes()?;
+        }
+
+        Ok(names)
+    }
+}
+
+impl Certificate {
+    // Parses a certificate in the `<type> <base64> [comment]` format of `*-cert.pub` files
+    pub fn parse(contents: &str) -> Result<Certificate, std::io::Error> {
+        let invalid = |error: &dyn std::fmt::Display| {
+            std::io::Error::other(format!("Invalid SSH certificate: {}", error))
+        };
+
+        let mut parts = contents.split_whitespace();
+        let encoded = match (parts.next(), parts.next()) {
+            (Some(key_type), Some(encoded)) if key_type.contains("-cert-v01@openssh.com") => {
+                encoded
+            }
+            _ => return Err(invalid(&"not an OpenSSH certificate")),
+        };
+        let blob = STANDARD.decode(encoded).map_err(|e| invalid(&e))?;
+        let mut reader = Reader(&blob);
+
+        let key_type = reader.string().map_err(|e| invalid(&e))?;
+
+        // The public key fields before the serial differ by key type
+        let key_fields = match key_type.as_str() {
+            "ssh-rsa-cert-v01@openssh.com" => 2,
+            "ssh-dss-cert-v01@openssh.com" => 4,
+            "ssh-ed25519-cert-v01@openssh.com" => 1,
+            "sk-ssh-ed25519-cert-v01@openssh.com" => 2,
+            "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com" => 3,
+            t if t.starts_with("ecdsa-sha2-") => 2,
+            t => return Err(invalid(&format!("unsupported key type {}", t))),
+        };
+
+        let mut read = || -> Result<Certificate, std::io::Error> {
+            // Nonce
+            reader.bytes()?;
+            for _ in 0..key_fields {
+                reader.bytes()?;
+            }
+            // Serial and certificate type
+            reader.u64()?;
+            reader.u32()?;
+
+            let key_id = reader.string()?;
+            let principals = reader.strings()?;
+            let valid_after = reader.u64()?;
+            let valid_before = reader.u64()?;
+            // Critical options
+            reader.bytes()?;
+            let extensions = reader.names()?;
+            // Reserved, the key of the CA and the signature, which are only read so a truncated certificate is an error
+            reader.bytes()?;
+            reader.bytes()?;
+            reader.bytes()?;
+
+            Ok(Certificate {
+                key_type: key_type.to_string(),
+                key_id,
+                principals,
+                valid_after,
+                valid_before,
+                extensions,
+            })
+        };
+
+        read().map_err(|e| invalid(&e))
+    }
+
+    pub fn load(path: &Path) -> Result<Certificate, std::io::Error> {
+        let contents = read_to_string(path).map_err(|error| {
+            std::io::Error::other(format!("Unable to read {}: {}", path.display(), error))
+        })?;
+
+        Certificate::parse(&contents)
+            .map_err(|error| std::io::Error::other(format!("{}: {}", path.display(), error)))
+    }
+
+    // Whether the certificate may be used to log in as `username`
+    pub fn permits(&self, username: &str) -> bool {
+        self.principals.is_empty() || self.principals.iter().any(|p| p == username)
+    }
+
+    // Checks the certificate can be used as `username` now, and stays valid for at least `min_validity`
+    pub fn validate(&self, username: &str, min_validity: Duration) -> Result<(), std::io::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        if now < self.valid_after {
+            return Err(std::io::Error::other(format!(
+                "The Machine ID certificate is not valid yet, it becomes valid in {}. Check the clock of the Drone runner.",
+                describe_duration(self.valid_after - now)
+            )));
+        }
+
+        if now >= self.valid_before {
+            return Err(std::io::Error::other(format!(
+                "The Machine ID certificate expired {} ago. Check that tbot is running and renewing the identity.",
+                describe_duration(now - self.valid_before)
+            )));
+        }
+
+        if self.valid_before - now < min_validity.as_secs() {
+            return Err(std::io::Error::other(format!(
+                "The Machine ID certificate expires in {}, which is less than the required {}. Check that tbot is renewing the identity.",
+                describe_duration(self.valid_before - now),
+                describe_duration(min_validity.as_secs())
+            )));
+        }
+
+        if !self.permits(username) {
+            return Err(std::io::Error::other(format!(
+                "The Machine ID certificate doesn't permit logging in as {}. Allowed principals: {}",
+                username,
+                self.principals.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    // How long until the certificate expires, for display
+    pub fn describe_expiry(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        match self.valid_before {
+            u64::MAX => String::from("never expires"),
+            before if before > now => format!("expires in {}", describe_duration(before - now)),
+            before => format!("expired {} ago", describe_duration(now - before)),
+        }
+    }
+}
+
+// Formats a number of seconds as a rough human readable duration
+fn describe_duration(seconds: u64) -> String {
+    match seconds {
+        s if s < 120 => format!("{} seconds", s),
+        s if s < 2 * 3600 => format!("{} minutes", s / 60),
+        s if s < 2 * 86400 => format!("{} hours", s / 3600),
+        s => format!("{} days", s / 86400),
+    }
+}
+
+// Finds the SSH certificate tbot wrote to the destination directory
+pub fn find_certificate(data_path: &str) -> Option<PathBuf> {
+    let preferred = Path::new(data_path).join("key-cert.pub");
+    if preferred.is_file() {
+        return Some(preferred);
+    }
+
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(data_path)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().ends_with("-cert.pub"))
+                    .unwrap_or(false)
+        })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next()
+}
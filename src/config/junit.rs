@@ -0,0 +1,153 @@
+use colored::Colorize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::config::report::{HostResult, HostStatus, StepResult};
+
+// Escapes text for XML, dropping characters XML can't represent such as terminal escape sequences
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+// A testcase for a command that ran
+fn step_testcase(host: &str, step: &StepResult) -> String {
+    let mut testcase = format!(
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\">\n",
+        escape(&step.name),
+        escape(host),
+        seconds(step.duration)
+    );
+
+    match (&step.error, step.exit_code) {
+        (Some(error), _) => testcase.push_str(&format!(
+            "      <error message=\"{}\" type=\"error\"/>\n",
+            escape(error)
+        )),
+        (None, Some(code)) if code != 0 => testcase.push_str(&format!(
+            "      <failure message=\"exited with status {}\" type=\"exit\"/>\n",
+            code
+        )),
+        _ => {}
+    }
+
+    if !step.stdout.is_empty() {
+        testcase.push_str(&format!(
+            "      <system-out>{}</system-out>\n",
+            escape(&step.stdout)
+        ));
+    }
+    if !step.stderr.is_empty() {
+        testcase.push_str(&format!(
+            "      <system-err>{}</system-err>\n",
+            escape(&step.stderr)
+        ));
+    }
+
+    testcase.push_str("    </testcase>\n");
+    testcase
+}
+
+// A testsuite for a host, with a testcase for every command. Commands that never ran are skipped.
+fn testsuite(result: &HostResult, commands: &[String]) -> String {
+    let mut testcases = String::new();
+    let (mut tests, mut failures, mut errors, mut skipped) = (0, 0, 0, 0);
+
+    // A host that failed before running any command, e.g. because it couldn't be connected to
+    if result.is_failed() && result.failed_command.is_none() {
+        tests += 1;
+        errors += 1;
+        testcases.push_str(&format!(
+            "    <testcase name=\"connect\" classname=\"{}\" time=\"{}\">\n      <error message=\"{}\" type=\"error\"/>\n    </testcase>\n",
+            escape(&result.host),
+            seconds(result.duration),
+            escape(result.error.as_deref().unwrap_or_default())
+        ));
+    }
+
+    for step in result.steps.iter() {
+        tests += 1;
+        match (&step.error, step.exit_code) {
+            (Some(_), _) => errors += 1,
+            (None, Some(code)) if code != 0 => failures += 1,
+            _ => {}
+        }
+        testcases.push_str(&step_testcase(&result.host, step));
+    }
+
+    let reason = match result.status {
+        HostStatus::Skipped => "the rollout stopped before this host",
+        HostStatus::Cancelled => "another host failed",
+        _ if result.failed_command.is_none() => "the host failed before any command ran",
+        _ => "an earlier command failed",
+    };
+    for command in commands.iter().skip(result.steps.len()) {
+        tests += 1;
+        skipped += 1;
+        testcases.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"0.000\">\n      <skipped message=\"not run: {}\"/>\n    </testcase>\n",
+            escape(command),
+            escape(&result.host),
+            reason
+        ));
+    }
+
+    format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{}\">\n{}  </testsuite>\n",
+        escape(&result.host),
+        tests,
+        failures,
+        errors,
+        skipped,
+        seconds(result.duration),
+        testcases
+    )
+}
+
+// Writes JUnit XML describing every command that was run on every host to `path`
+pub fn write_junit(
+    path: &str,
+    results: &[HostResult],
+    commands: &HashMap<String, Arc<Vec<String>>>,
+) {
+    let suites: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let commands = commands
+                .get(&result.host)
+                .map(|commands| commands.as_slice())
+                .unwrap_or_default();
+            testsuite(result, commands)
+        })
+        .collect();
+
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"drone-teleport\">\n{}</testsuites>\n",
+        suites.join("")
+    );
+
+    if let Err(error) = std::fs::write(path, xml) {
+        println!(
+            "{} {}: {}",
+            "Unable to write JUnit XML to".red().bold(),
+            path,
+            error
+        );
+    }
+}
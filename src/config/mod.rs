@@ -1,3 +1,15 @@
-pub(crate) mod connect;
-pub(crate) mod state;
-pub(crate) mod transfer;
+pub mod card;
+pub mod connect;
+pub mod console;
+pub mod fetch;
+pub mod identity;
+pub mod junit;
+pub mod nodes;
+pub mod plan;
+pub mod report;
+pub mod rollout;
+pub mod shell;
+pub mod ssh;
+pub mod state;
+pub mod template;
+pub mod transfer;
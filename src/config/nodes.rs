@@ -0,0 +1,282 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, process::Command};
+
+use crate::config::state::Config;
+
+/// Teleport node labels, by name
+pub type Labels = BTreeMap<String, String>;
+
+/// A Teleport node, as printed by `tsh ls --format=json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Node {
+    pub metadata: NodeMetadata,
+    pub spec: NodeSpec,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeSpec {
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub cmd_labels: BTreeMap<String, CommandLabel>,
+}
+
+/// A label whose value is the output of a command run periodically on the node
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandLabel {
+    #[serde(default)]
+    pub result: String,
+}
+
+impl Node {
+    // The name to connect to the node with
+    pub fn get_host(&self) -> String {
+        match self.spec.hostname.is_empty() {
+            true => self.metadata.name.to_string(),
+            false => self.spec.hostname.to_string(),
+        }
+    }
+
+    // Static and command labels of the node
+    pub fn get_labels(&self) -> Labels {
+        let mut labels = self.metadata.labels.clone();
+        for (key, label) in &self.spec.cmd_labels {
+            labels.insert(key.to_string(), label.result.trim().to_string());
+        }
+
+        labels
+    }
+}
+
+// Parses a label selector such as `env=prod,role=web` into its key/value pairs
+pub fn parse_labels(selector: &str) -> Result<Labels, std::io::Error> {
+    let mut labels = BTreeMap::new();
+    for pair in selector.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                labels.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "Invalid host label `{}` in `{}`. Expected key=value pairs separated by commas, e.g. env=prod,role=web",
+                    pair, selector
+                )))
+            }
+        }
+    }
+
+    Ok(labels)
+}
+
+// Runs `tsh ls`, or host_query_command when it is set, and parses the nodes it prints
+fn list_nodes(cfg: &Config) -> Result<Vec<Node>, std::io::Error> {
+    let output = match &cfg.host_query_command {
+        Some(command) => Command::new("sh").arg("-c").arg(command).output(),
+        None => {
+            let mut tsh = Command::new("tsh");
+            tsh.arg("ls")
+                .arg("--format=json")
+                .arg(format!("--proxy={}", cfg.proxy))
+                .arg(format!("--identity={}/identity", cfg.data_path));
+
+            if let Some(cluster) = &cfg.cluster {
+                tsh.arg(format!("--cluster={}", cluster));
+            }
+
+            if let Some(labels) = &cfg.host_labels {
+                tsh.arg(labels);
+            }
+
+            if let Some(query) = &cfg.host_query {
+                tsh.arg(format!("--query={}", query));
+            }
+
+            tsh.output()
+        }
+    }
+    .map_err(|error| std::io::Error::other(format!("Unable to list Teleport nodes: {}", error)))?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "Unable to list Teleport nodes (exit {}): {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|error| {
+        std::io::Error::other(format!(
+            "Unable to parse the Teleport node list as `tsh ls --format=json` output: {}",
+            error
+        ))
+    })
+}
+
+// Finds the nodes matching host_labels and host_query, returning their host names and labels
+pub fn resolve(cfg: &Config) -> Result<Vec<(String, Labels)>, std::io::Error> {
+    let selector = match &cfg.host_labels {
+        Some(labels) => parse_labels(labels)?,
+        None => BTreeMap::new(),
+    };
+
+    // tsh already filters by labels, but a host_query_command may print every node
+    let nodes: Vec<(String, Labels)> = list_nodes(cfg)?
+        .iter()
+        .map(|node| (node.get_host(), node.get_labels()))
+        .filter(|(_, labels)| {
+            selector
+                .iter()
+                .all(|(key, value)| labels.get(key) == Some(value))
+        })
+        .collect();
+
+    if nodes.is_empty() {
+        let mut selectors: Vec<String> = Vec::new();
+        if let Some(labels) = &cfg.host_labels {
+            selectors.push(format!("labels {}", labels));
+        }
+        if let Some(query) = &cfg.host_query {
+            selectors.push(format!("query `{}`", query));
+        }
+
+        return Err(std::io::Error::other(format!(
+            "No Teleport nodes matched {}",
+            selectors.join(" and ")
+        )));
+    }
+
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    // Three nodes as `tsh ls --format=json` prints them, one with a command label and one without a hostname
+    const NODES: &str = r#"[
+        {
+            "kind": "node",
+            "metadata": { "name": "5a1c2e0d", "labels": { "env": "prod", "role": "web" } },
+            "spec": { "hostname": "web1", "addr": "10.0.0.1:3022" }
+        },
+        {
+            "kind": "node",
+            "metadata": { "name": "7b3d4f1e", "labels": { "env": "staging", "role": "web" } },
+            "spec": { "hostname": "web2", "cmd_labels": { "arch": { "period": "1h0m0s", "result": "x86_64\n" } } }
+        },
+        {
+            "kind": "node",
+            "metadata": { "name": "db1", "labels": { "env": "prod", "role": "db", "tier": "" } },
+            "spec": {}
+        }
+    ]"#;
+
+    // Resolves the nodes in NODES matching `host_labels`, listed by a host_query_command
+    fn resolve_nodes(host_labels: &str) -> Result<Vec<(String, Labels)>, std::io::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.json");
+        std::fs::write(&path, NODES).unwrap();
+
+        let cfg = Config::try_parse_from([
+            "drone-teleport",
+            "--username=bot",
+            "--proxy=teleport.example.com:443",
+            "--data-path=/opt/teleport/home",
+            &format!("--host-labels={}", host_labels),
+            &format!("--host-query-command=cat {}", path.display()),
+            "connect",
+        ])
+        .unwrap();
+        resolve(&cfg)
+    }
+
+    // The host names of resolved nodes
+    fn hosts(nodes: &[(String, Labels)]) -> Vec<&str> {
+        nodes.iter().map(|(host, _)| host.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_labels() {
+        let labels = parse_labels("env=prod, role = web").unwrap();
+
+        assert_eq!(
+            labels,
+            Labels::from([
+                ("env".to_string(), "prod".to_string()),
+                ("role".to_string(), "web".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_empty_values_and_skips_empty_pairs() {
+        let labels = parse_labels("tier=,,env=prod,").unwrap();
+
+        assert_eq!(
+            labels,
+            Labels::from([
+                ("env".to_string(), "prod".to_string()),
+                ("tier".to_string(), String::new()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_labels_without_a_value() {
+        let error = parse_labels("env=prod,web").unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("Invalid host label `web` in `env=prod,web`."));
+    }
+
+    #[test]
+    fn rejects_labels_without_a_key() {
+        assert!(parse_labels("=prod").is_err());
+        assert!(parse_labels(" =prod").is_err());
+    }
+
+    #[test]
+    fn filters_listed_nodes_by_labels() {
+        let nodes = resolve_nodes("role=web").unwrap();
+
+        assert_eq!(hosts(&nodes), ["web1", "web2"]);
+        assert_eq!(hosts(&resolve_nodes("env=prod").unwrap()), ["web1", "db1"]);
+        assert_eq!(
+            hosts(&resolve_nodes("env=prod,role=web").unwrap()),
+            ["web1"]
+        );
+    }
+
+    #[test]
+    fn filters_listed_nodes_by_command_labels() {
+        let nodes = resolve_nodes("arch=x86_64").unwrap();
+
+        assert_eq!(hosts(&nodes), ["web2"]);
+        assert_eq!(nodes[0].1["arch"], "x86_64");
+        assert_eq!(nodes[0].1["env"], "staging");
+    }
+
+    #[test]
+    fn filters_listed_nodes_by_empty_labels() {
+        assert_eq!(hosts(&resolve_nodes("tier=").unwrap()), ["db1"]);
+    }
+
+    #[test]
+    fn fails_when_no_listed_node_matches() {
+        let error = resolve_nodes("env=prod,role=cache").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "No Teleport nodes matched labels env=prod,role=cache"
+        );
+    }
+}
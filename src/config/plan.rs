@@ -0,0 +1,217 @@
+use colored::Colorize;
+use human_bytes::human_bytes;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use crate::{
+    config::{rollout, state::Config},
+    engine::TransferEngine,
+    runner::CommandRunner,
+};
+
+// Exported values are never printed, as they usually hold secrets
+const MASK: &str = "********";
+
+// Prints the hosts the run would go through, batch by batch
+fn print_hosts(cfg: &Config) {
+    println!(
+        "{} {}",
+        "Dry run:".yellow().bold(),
+        "nothing is connected to or changed on any host".italic()
+    );
+
+    let batches = rollout::get_batches(cfg);
+    for (index, batch) in batches.iter().enumerate() {
+        println!(
+            "{} {}/{}: {}",
+            "Batch".bold(),
+            index + 1,
+            batches.len(),
+            batch.join(", ").cyan()
+        );
+    }
+}
+
+// Prints the exact command lines that connect would run on every host, with the values of exported variables masked
+pub fn print_commands(
+    cfg: &Config,
+    runner: &CommandRunner,
+    vars: &BTreeMap<String, String>,
+    rendered: &HashMap<String, Arc<Vec<String>>>,
+) -> i32 {
+    print_hosts(cfg);
+
+    let masked: BTreeMap<String, String> = vars
+        .keys()
+        .map(|name| (name.to_string(), MASK.to_string()))
+        .collect();
+    let mut runner = runner.clone();
+    runner.env(&masked);
+
+    if !vars.is_empty() {
+        println!(
+            "{} {}",
+            "Exports:".bold(),
+            vars.keys().cloned().collect::<Vec<String>>().join(", ")
+        );
+    }
+
+    for host in &cfg.hosts {
+        println!("\n{} {}", "Host".bold(), host.cyan());
+        for command in rendered[host].iter() {
+            println!("  {} {}", "$".green(), command);
+        }
+
+        println!("  {}", "Runs:".bold());
+        for line in runner.command_lines(&rendered[host]) {
+            println!("    {}", line.italic());
+        }
+    }
+
+    0
+}
+
+// Prints what transfer would upload to every host: the files matched by each src, the archive they are packed into,
+// and where it is extracted. Returns 1 if a src can't be expanded.
+pub fn print_transfers(
+    cfg: &Config,
+    engine: &TransferEngine,
+    rendered: &HashMap<String, Arc<HashMap<String, String>>>,
+) -> i32 {
+    print_hosts(cfg);
+
+    // Like a real transfer, every src is archived once and shared by all hosts, unless it is streamed
+    let mut plans = HashMap::new();
+    for files in rendered.values() {
+        for src in files.keys() {
+            if !plans.contains_key(src) {
+                plans.insert(src.to_string(), engine.plan(src));
+            }
+        }
+    }
+
+    let mut code = 0;
+    for host in &cfg.hosts {
+        println!("\n{} {}", "Host".bold(), host.cyan());
+        for (src, dst) in rendered[host].iter() {
+            println!("  {} => {}", src.green(), dst.green());
+
+            let plan = match &plans[src] {
+                Ok(plan) => plan,
+                Err(error) => {
+                    println!("    {}", error.to_string().red().bold());
+                    code = 1;
+                    continue;
+                }
+            };
+
+            if plan.files.is_empty() {
+                println!("    {}", "No files match".yellow());
+            }
+            for (path, size) in plan.files.iter() {
+                println!("    {} ({})", path.display(), human_bytes(*size as f64));
+            }
+
+            println!(
+                "    {} {} in {} files",
+                "Total:".bold(),
+                human_bytes(plan.size() as f64),
+                plan.files.len()
+            );
+            match engine.is_streaming() {
+                true => println!(
+                    "    {} {}",
+                    "Archive:".bold(),
+                    "streamed as it is built".italic()
+                ),
+                false => println!("    {} {}", "Archive:".bold(), plan.upload),
+            }
+            let destination = engine.destination(dst);
+            println!(
+                "    {} {}",
+                "Uploads to:".bold(),
+                plan.remote_path(&destination)
+            );
+            println!(
+                "    {} {}",
+                "Extracts with:".bold(),
+                plan.extract(&destination).italic()
+            );
+        }
+
+        // The release is only switched to once every file was extracted
+        if let Some(release) = engine.get_release() {
+            let dsts: BTreeSet<&String> = rendered[host].values().collect();
+            for dst in dsts {
+                println!("  {} {}", "Release".bold(), release.dir(dst).green());
+                println!(
+                    "    {} {}",
+                    "Prepares with:".bold(),
+                    release.prepare(dst).italic()
+                );
+                if let Some(share) = release.share(dst) {
+                    println!("    {} {}", "Shares with:".bold(), share.italic());
+                }
+                println!(
+                    "    {} {}",
+                    "Switches with:".bold(),
+                    release.switch(dst).italic()
+                );
+                match release.prune(dst) {
+                    Some(prune) => println!("    {} {}", "Prunes with:".bold(), prune.italic()),
+                    None => println!(
+                        "    {} {}",
+                        "Prunes with:".bold(),
+                        "every release is kept".italic()
+                    ),
+                }
+            }
+        }
+    }
+
+    println!(
+        "\n{}",
+        "Archive names are random, so they differ from run to run.".italic()
+    );
+
+    code
+}
+
+// Prints what fetch would download from every host: the command that archives each src on the host, and the local
+// directory it is extracted into. The src globs are expanded by the host, so the files they match aren't listed.
+pub fn print_fetches(
+    cfg: &Config,
+    engine: &TransferEngine,
+    rendered: &HashMap<String, Arc<HashMap<String, String>>>,
+) -> i32 {
+    print_hosts(cfg);
+
+    for host in &cfg.hosts {
+        println!("\n{} {}", "Host".bold(), host.cyan());
+        for (src, dst) in rendered[host].iter() {
+            let archive = engine.fetch_name();
+            let local = std::path::Path::new(dst).join(host);
+
+            println!(
+                "  {} => {}",
+                src.green(),
+                local.display().to_string().green()
+            );
+            println!(
+                "    {} {}",
+                "Archives with:".bold(),
+                engine.fetch_command(src, &archive).italic()
+            );
+            println!("    {} {}", "Downloads:".bold(), archive);
+        }
+    }
+
+    println!(
+        "\n{}",
+        "Archive names are random, so they differ from run to run.".italic()
+    );
+
+    0
+}
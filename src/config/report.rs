@@ -0,0 +1,355 @@
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::{
+    config::{card, state::Config},
+    error::Error,
+};
+
+// The version of the JSON report schema, incremented whenever a field is changed or removed
+const REPORT_VERSION: u32 = 1;
+
+/// The final state of a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostStatus {
+    Succeeded,
+    Failed,
+    Cancelled,
+    Skipped,
+}
+
+impl HostStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HostStatus::Succeeded => "succeeded",
+            HostStatus::Failed => "failed",
+            HostStatus::Cancelled => "cancelled",
+            HostStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// The outcome of a single command, or file transfer, on a host
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    /// The command, or the src of the file
+    pub name: String,
+    /// The exit status of the command
+    pub exit_code: Option<i32>,
+    /// A description of the failure
+    pub error: Option<String>,
+    pub duration: Duration,
+    /// The number of bytes uploaded
+    pub bytes: Option<u64>,
+    /// The number of bytes before compression
+    pub size: Option<u64>,
+    /// The output of the command, when it was captured
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl StepResult {
+    pub fn new(name: &str) -> StepResult {
+        StepResult {
+            name: name.to_string(),
+            exit_code: None,
+            error: None,
+            duration: Duration::ZERO,
+            bytes: None,
+            size: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.error.is_some() || self.exit_code.unwrap_or(0) != 0
+    }
+}
+
+/// The outcome of running an operation against a single host
+#[derive(Debug, Clone)]
+pub struct HostResult {
+    pub host: String,
+    pub status: HostStatus,
+    /// Whether a connection to the host was established
+    pub connected: bool,
+    /// The number of commands run, or files transferred, successfully
+    pub completed: usize,
+    /// The command, or file, that failed
+    pub failed_command: Option<String>,
+    /// The exit status of the failed remote command
+    pub exit_code: Option<i32>,
+    /// A description of the failure
+    pub error: Option<String>,
+    /// The exit code the plugin should exit with because of this host
+    pub code: i32,
+    pub duration: Duration,
+    /// Every command run, or file transferred, in order
+    pub steps: Vec<StepResult>,
+}
+
+impl HostResult {
+    pub fn new(host: &str) -> HostResult {
+        HostResult {
+            host: host.to_string(),
+            status: HostStatus::Succeeded,
+            connected: false,
+            completed: 0,
+            failed_command: None,
+            exit_code: None,
+            error: None,
+            code: 0,
+            duration: Duration::ZERO,
+            steps: Vec::new(),
+        }
+    }
+
+    // A host that was never started because the rollout stopped
+    pub fn skipped(host: &str) -> HostResult {
+        let mut result = HostResult::new(host);
+        result.status = HostStatus::Skipped;
+        result
+    }
+
+    // Marks the host as failed with the plugin exit code `code`
+    pub fn fail(&mut self, code: i32, command: Option<&str>, error: Option<String>) {
+        self.status = HostStatus::Failed;
+        self.code = code;
+        self.failed_command = command.map(|command| command.to_string());
+        self.error = error;
+    }
+
+    // Marks the host as failed because of `error`, or as cancelled when it stopped because another host failed
+    pub fn fail_with(&mut self, error: &Error) {
+        match error {
+            Error::Cancelled => self.cancel(),
+            Error::CommandFailed { command, exit_code } => {
+                self.fail(error.exit_code(), Some(command), None);
+                self.exit_code = *exit_code;
+            }
+            error => self.fail(error.exit_code(), error.command(), Some(error.to_string())),
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        self.status = HostStatus::Cancelled;
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status == HostStatus::Failed
+    }
+}
+
+// The exit code for the whole run, taken from the first failed host
+pub fn exit_code(results: &[HostResult]) -> i32 {
+    if let Some(failed) = results.iter().find(|result| result.is_failed()) {
+        return failed.code;
+    }
+
+    match results
+        .iter()
+        .all(|result| result.status == HostStatus::Succeeded)
+    {
+        true => 0,
+        false => 1,
+    }
+}
+
+// Describes the failure of a host, from the command that failed and the error
+pub fn describe_failure(result: &HostResult) -> String {
+    match (&result.failed_command, result.exit_code, &result.error) {
+        (Some(command), Some(code), _) => format!("{} (exit {})", command, code),
+        (Some(command), None, Some(error)) => format!("{}: {}", command, error),
+        (Some(command), None, None) => command.to_string(),
+        (None, _, Some(error)) => error.to_string(),
+        (None, _, None) => String::new(),
+    }
+}
+
+// Rounds a duration to milliseconds, in seconds
+fn seconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.0).round() / 1000.0
+}
+
+// Builds the JSON report of a run. The schema is documented in the README, and REPORT_VERSION must be incremented when it changes.
+pub fn build_report(cfg: &Config, results: &[HostResult], error: Option<&str>) -> Value {
+    let code = match error {
+        Some(_) => 1,
+        None => exit_code(results),
+    };
+
+    let hosts: Vec<Value> = results
+        .iter()
+        .map(|result| {
+            let steps: Vec<Value> = result
+                .steps
+                .iter()
+                .map(|step| {
+                    let ratio = match (step.size, step.bytes) {
+                        (Some(size), Some(bytes)) if bytes > 0 => {
+                            Some((size as f64 / bytes as f64 * 100.0).round() / 100.0)
+                        }
+                        _ => None,
+                    };
+
+                    json!({
+                        "name": step.name,
+                        "status": match step.is_failed() {
+                            true => "failed",
+                            false => "succeeded",
+                        },
+                        "exit_code": step.exit_code,
+                        "duration": seconds(step.duration),
+                        "bytes": step.bytes,
+                        "size": step.size,
+                        "compression_ratio": ratio,
+                        "error": step.error,
+                    })
+                })
+                .collect();
+
+            json!({
+                "host": result.host,
+                "status": result.status.as_str(),
+                "connected": result.connected,
+                "completed": result.completed,
+                "duration": seconds(result.duration),
+                "code": result.code,
+                "failure": match result.is_failed() {
+                    true => json!({
+                        "name": result.failed_command,
+                        "exit_code": result.exit_code,
+                        "error": result.error,
+                        "message": describe_failure(result),
+                    }),
+                    false => Value::Null,
+                },
+                "steps": steps,
+            })
+        })
+        .collect();
+
+    json!({
+        "version": REPORT_VERSION,
+        "operation": cfg.get_operation(),
+        "status": match code {
+            0 => "succeeded",
+            _ => "failed",
+        },
+        "exit_code": code,
+        "error": error,
+        "started_at": cfg.started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        "duration": seconds(cfg.started.elapsed().unwrap_or_default()),
+        "hosts": hosts,
+    })
+}
+
+// Writes the JSON report to report_path, when it is set
+pub fn write_report(cfg: &Config, results: &[HostResult], error: Option<&str>) {
+    if let Some(path) = &cfg.report_path {
+        let report = build_report(cfg, results, error);
+        let written = serde_json::to_string_pretty(&report)
+            .map_err(std::io::Error::other)
+            .and_then(|report| std::fs::write(path, report));
+
+        if let Err(error) = written {
+            println!(
+                "{} {}: {}",
+                "Unable to write the report to".red().bold(),
+                path,
+                error
+            );
+        }
+    }
+}
+
+// Prints the summary and writes the report, returning the code the plugin exits with
+pub fn finish(cfg: &Config, results: &[HostResult], completed_label: &str) -> i32 {
+    print_summary(results, completed_label);
+    write_report(cfg, results, None);
+    card::write_card(cfg, results, None);
+    exit_code(results)
+}
+
+// Fails the run before any host was started, still writing the report, and returns the error it failed with
+pub fn abort(cfg: &Config, error: Error) -> Error {
+    let message = error.to_string();
+    println!("{}", message.red().bold());
+    write_report(cfg, &[], Some(&message));
+    card::write_card(cfg, &[], Some(&message));
+    error
+}
+
+// Prints a table describing what happened on every host. `completed_label` names what `HostResult::completed` counts.
+pub fn print_summary(results: &[HostResult], completed_label: &str) {
+    let headers = [
+        "Host",
+        "Status",
+        "Connected",
+        completed_label,
+        "Duration",
+        "Failure",
+    ];
+
+    let rows: Vec<[String; 6]> = results
+        .iter()
+        .map(|result| {
+            let failure = describe_failure(result);
+
+            [
+                result.host.to_string(),
+                result.status.as_str().to_string(),
+                match result.connected {
+                    true => String::from("yes"),
+                    false => String::from("no"),
+                },
+                result.completed.to_string(),
+                format!("{:.1}s", result.duration.as_secs_f64()),
+                failure,
+            ]
+        })
+        .collect();
+
+    let mut widths = headers.map(|header| header.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    println!("\n{}", "Summary".bold());
+    let header: Vec<String> = headers
+        .iter()
+        .zip(widths.iter())
+        .map(|(header, width)| format!("{:<width$}", header, width = width))
+        .collect();
+    println!("{}", header.join("  ").trim_end().bold());
+
+    for (row, result) in rows.iter().zip(results.iter()) {
+        let columns: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(index, (column, width))| {
+                // The last column isn't padded so lines don't end in whitespace
+                let column = match index == row.len() - 1 {
+                    true => column.to_string(),
+                    false => format!("{:<width$}", column, width = width),
+                };
+                match index {
+                    0 => column.yellow().to_string(),
+                    1 => match result.status {
+                        HostStatus::Succeeded => column.green().to_string(),
+                        HostStatus::Failed => column.red().bold().to_string(),
+                        HostStatus::Cancelled | HostStatus::Skipped => column.dimmed().to_string(),
+                    },
+                    5 if !column.is_empty() => column.red().to_string(),
+                    _ => column,
+                }
+            })
+            .collect();
+        println!("{}", columns.join("  ").trim_end());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::{report::HostResult, state::Config, template::Context};
+
+use colored::Colorize;
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// The number of hosts to process at the same time, as an absolute number or a percentage of all hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchSize {
+    Hosts(usize),
+    Percent(usize),
+}
+
+impl BatchSize {
+    // Parses `batch_size` as either `N` hosts, or `N%` of the hosts
+    pub fn parse(arg: &str) -> Result<BatchSize, std::io::Error> {
+        let arg = arg.trim();
+        let invalid = || {
+            std::io::Error::other(format!(
+                "Invalid batch size `{}`. Expected a number of hosts (e.g. 2) or a percentage (e.g. 25%).",
+                arg
+            ))
+        };
+
+        let size = match arg.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<usize>() {
+                Ok(percent) if percent > 0 && percent <= 100 => BatchSize::Percent(percent),
+                _ => return Err(invalid()),
+            },
+            None => match arg.parse::<usize>() {
+                Ok(hosts) if hosts > 0 => BatchSize::Hosts(hosts),
+                _ => return Err(invalid()),
+            },
+        };
+
+        Ok(size)
+    }
+
+    // The number of hosts in each batch, out of `total` hosts. Batches always contain at least one host.
+    pub fn hosts_per_batch(&self, total: usize) -> usize {
+        let size = match self {
+            BatchSize::Hosts(hosts) => *hosts,
+            BatchSize::Percent(percent) => (total * percent).div_ceil(100),
+        };
+
+        size.clamp(1, total.max(1))
+    }
+}
+
+/// What to do with the remaining hosts once a host fails
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop every other host and skip any remaining batches. A script is terminated on the host right away, while in
+    /// commands mode a host stops once its current command finishes.
+    #[clap(alias = "fail_fast")]
+    FailFast,
+    /// Let every host in the batch finish before failing
+    #[clap(alias = "run_all")]
+    RunAll,
+}
+
+/// Signals in-flight host tasks that they should stop at the next safe point
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Splits the configured hosts into the batches they should be processed in
+pub fn get_batches(cfg: &Config) -> Vec<Vec<String>> {
+    let size = cfg.batch_size.hosts_per_batch(cfg.hosts.len());
+    cfg.hosts.chunks(size).map(|batch| batch.to_vec()).collect()
+}
+
+// The template context of every host, in host order
+pub fn get_contexts(cfg: &Config) -> Vec<Context> {
+    get_batches(cfg)
+        .iter()
+        .enumerate()
+        .flat_map(|(batch, hosts)| hosts.iter().map(move |host| (batch + 1, host.to_owned())))
+        .enumerate()
+        .map(|(host_index, (batch, host))| Context {
+            labels: cfg.host_node_labels.get(&host).cloned().unwrap_or_default(),
+            env: cfg.runner_env.clone(),
+            host,
+            host_index,
+            batch,
+        })
+        .collect()
+}
+
+// Runs `spawn` for every host, one batch at a time, and returns the result of every host in host order.
+// Hosts that were never started because the rollout stopped are reported as skipped.
+pub async fn run<F>(cfg: &Config, mut spawn: F) -> Vec<HostResult>
+where
+    F: FnMut(String, Cancellation) -> JoinHandle<HostResult>,
+{
+    let batches = get_batches(cfg);
+    let cancellation = Cancellation::default();
+    let mut results: Vec<HostResult> = Vec::new();
+
+    for (index, batch) in batches.iter().enumerate() {
+        if batches.len() > 1 {
+            println!(
+                "{} {}/{}: {}",
+                "Batch".bold(),
+                index + 1,
+                batches.len(),
+                batch.join(", ").cyan()
+            );
+        }
+
+        // Every host in the batch runs in parallel. Results are handled as they complete so a failure can cancel the others right away.
+        let mut tasks: FuturesUnordered<_> = batch
+            .iter()
+            .map(|host| {
+                let task = spawn(host.to_owned(), cancellation.clone());
+                let host = host.to_owned();
+                async move {
+                    // A task that panicked is treated as a generic failure
+                    task.await.unwrap_or_else(|error| {
+                        let mut result = HostResult::new(&host);
+                        result.fail(1, None, Some(error.to_string()));
+                        result
+                    })
+                }
+            })
+            .collect();
+
+        let mut failed = false;
+        while let Some(result) = tasks.next().await {
+            if result.is_failed() {
+                failed = true;
+                if cfg.failure_policy == FailurePolicy::FailFast && !cancellation.is_cancelled() {
+                    println!(
+                        "{} Cancelling all other hosts.",
+                        format!("{} failed.", result.host).red().bold()
+                    );
+                    cancellation.cancel();
+                }
+            }
+            results.push(result);
+        }
+
+        let remaining = &batches[index + 1..];
+        if remaining.is_empty() {
+            break;
+        }
+
+        if failed && (cancellation.is_cancelled() || cfg.batch_stop_on_failure) {
+            println!(
+                "{} Stopping the rollout, skipped hosts: {}",
+                format!("Batch {}/{} failed.", index + 1, batches.len())
+                    .red()
+                    .bold(),
+                remaining.concat().join(", ").cyan()
+            );
+            results.extend(
+                remaining
+                    .concat()
+                    .iter()
+                    .map(|host| HostResult::skipped(host)),
+            );
+            break;
+        }
+
+        if cfg.batch_pause > 0 {
+            println!(
+                "Pausing {} seconds before the next batch.",
+                cfg.batch_pause.to_string().bold()
+            );
+            tokio::time::sleep(Duration::from_secs(cfg.batch_pause)).await;
+        }
+    }
+
+    // Report hosts in the order they were configured in, rather than the order they finished
+    results.sort_by_key(|result| cfg.hosts.iter().position(|host| *host == result.host));
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    // The settings to roll out to `hosts` with, `batch_size` at a time
+    fn config(hosts: &[&str], batch_size: &str) -> Config {
+        Config::try_parse_from([
+            "drone-teleport",
+            "--username=bot",
+            "--proxy=teleport.example.com:443",
+            "--data-path=/opt/teleport/home",
+            &format!("--hosts={}", hosts.join(",")),
+            &format!("--batch-size={}", batch_size),
+            "connect",
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_a_number_of_hosts() {
+        assert_eq!(BatchSize::parse("2").unwrap(), BatchSize::Hosts(2));
+        assert_eq!(BatchSize::parse(" 3 ").unwrap(), BatchSize::Hosts(3));
+    }
+
+    #[test]
+    fn parses_a_percentage() {
+        assert_eq!(BatchSize::parse("25%").unwrap(), BatchSize::Percent(25));
+        assert_eq!(BatchSize::parse("100%").unwrap(), BatchSize::Percent(100));
+        assert_eq!(BatchSize::parse("1%").unwrap(), BatchSize::Percent(1));
+    }
+
+    #[test]
+    fn rejects_empty_batches() {
+        assert!(BatchSize::parse("0").is_err());
+        assert!(BatchSize::parse("0%").is_err());
+    }
+
+    #[test]
+    fn rejects_more_than_all_hosts() {
+        let error = BatchSize::parse("101%").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Invalid batch size `101%`. Expected a number of hosts (e.g. 2) or a percentage (e.g. 25%)."
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_batch_sizes() {
+        for arg in ["", "%", "-1", "2.5", "two", "50%%", "%50"] {
+            assert!(BatchSize::parse(arg).is_err(), "{:?} was accepted", arg);
+        }
+    }
+
+    #[test]
+    fn rounds_percentages_up() {
+        assert_eq!(BatchSize::Percent(25).hosts_per_batch(5), 2);
+        assert_eq!(BatchSize::Percent(25).hosts_per_batch(4), 1);
+        assert_eq!(BatchSize::Percent(1).hosts_per_batch(3), 1);
+        assert_eq!(BatchSize::Percent(100).hosts_per_batch(3), 3);
+    }
+
+    #[test]
+    fn splits_hosts_into_batches() {
+        let cfg = config(&["web1", "web2", "web3", "web4", "web5"], "2");
+
+        assert_eq!(
+            get_batches(&cfg),
+            [vec!["web1", "web2"], vec!["web3", "web4"], vec!["web5"]]
+        );
+    }
+
+    #[test]
+    fn splits_hosts_into_batches_by_percentage() {
+        let cfg = config(&["web1", "web2", "web3", "web4", "web5"], "25%");
+
+        assert_eq!(
+            get_batches(&cfg),
+            [vec!["web1", "web2"], vec!["web3", "web4"], vec!["web5"]]
+        );
+    }
+
+    #[test]
+    fn puts_every_host_in_one_batch_when_the_batch_is_larger() {
+        let cfg = config(&["web1", "web2", "web3"], "10");
+
+        assert_eq!(BatchSize::Hosts(10).hosts_per_batch(3), 3);
+        assert_eq!(get_batches(&cfg), [vec!["web1", "web2", "web3"]]);
+    }
+}
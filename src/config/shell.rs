@@ -0,0 +1,172 @@
+// Helpers for building command lines that are executed by the remote POSIX shell
+
+/// Quotes a value so it is passed to a POSIX `sh` as a single literal word
+pub fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c))
+    {
+        return value.to_string();
+    }
+
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// The quoting in effect at some point of a shell command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quoting {
+    /// Outside of any quotes
+    None,
+    /// Within `'...'`
+    Single,
+    /// Within `"..."`
+    Double,
+}
+
+/// The quoting in effect once a POSIX `sh` has read `text`, starting with `quoting`
+pub fn quoting_after(text: &str, mut quoting: Quoting) -> Quoting {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        quoting = match (quoting, c) {
+            // A backslash escapes the next character, except within single quotes
+            (Quoting::None | Quoting::Double, '\\') => {
+                chars.next();
+                quoting
+            }
+            (Quoting::None, '\'') => Quoting::Single,
+            (Quoting::None, '"') => Quoting::Double,
+            (Quoting::Single, '\'') | (Quoting::Double, '"') => Quoting::None,
+            _ => quoting,
+        };
+    }
+
+    quoting
+}
+
+/// Quotes a value inserted where `quoting` is in effect, so the shell reads it as literal text that stays within
+/// the surrounding word
+pub fn quote_within(value: &str, quoting: Quoting) -> String {
+    match quoting {
+        Quoting::None => quote(value),
+        Quoting::Single => value.replace('\'', r"'\''"),
+        Quoting::Double => {
+            value
+                .chars()
+                .fold(String::with_capacity(value.len()), |mut quoted, c| {
+                    if "\\\"$`".contains(c) {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                    quoted
+                })
+        }
+    }
+}
+
+/// Whether `name` may be used as a POSIX shell variable name
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Wraps a command with coreutils `timeout` so the remote process is terminated once the deadline passes.
+/// The process is sent SIGTERM at the deadline, and SIGKILL after `grace` additional seconds.
+pub fn with_timeout(command: &str, seconds: u64, grace: u64) -> String {
+    format!(
+        "timeout --kill-after={} {} sh -c {}",
+        grace,
+        seconds,
+        quote(command)
+    )
+}
+
+/// Runs a command as the leader of a new process group through util-linux `setsid`, when the host has it, so signals
+/// sent to the group of the command don't reach the shell or sshd that started it.
+pub fn with_process_group(command: &str) -> String {
+    format!(
+        "$(command -v setsid >/dev/null 2>&1 && echo setsid -w) {}",
+        command
+    )
+}
+
+/// Terminates the process group of a script started with `with_process_group`, or only the script itself when the
+/// host has no `setsid`
+pub fn terminate(pid: u32) -> String {
+    format!("kill -TERM -{0} 2>/dev/null || kill -TERM {0}", pid)
+}
+
+/// Printed by generated scripts before each command, followed by the command index and `MARKER_END`
+pub const MARKER_START: &str = "\u{1e}drone-teleport:";
+pub const MARKER_END: char = '\u{1e}';
+
+/// Printed in place of the command index when a command exceeds its deadline
+pub const MARKER_TIMEOUT: &str = "timeout";
+
+/// Printed in place of the command index when the script starts, followed by the process id of the script
+pub const MARKER_PID: &str = "pid=";
+
+// Prints a marker line from a generated script
+fn marker(value: &str, redirect: &str) -> String {
+    format!("printf '\\036drone-teleport:{}\\036\\n'{}", value, redirect)
+}
+
+/// Builds a script that runs every command in a single shell, so `cd`, variables and functions carry over between commands.
+/// A marker is printed before each command, and the script exits with the status of the first command that fails.
+/// When `deadline` is set to `(seconds, grace)`, a watchdog terminates the script if a single command runs longer than `seconds`.
+/// The watchdog signals the process group led by the script, so the script has to be started with `with_process_group`.
+/// The script prints its process id first, so it can be terminated in the same way with `terminate`.
+pub fn script(env: &str, commands: &[String], deadline: Option<(u64, u64)>) -> String {
+    // The process id lets the runner terminate the script, e.g. when another host failed
+    let mut lines: Vec<String> = vec![format!(
+        "printf '\\036drone-teleport:{}%s\\036\\n' \"$$\"",
+        MARKER_PID
+    )];
+    if !env.trim().is_empty() {
+        lines.push(env.to_string());
+    }
+
+    if deadline.is_some() {
+        // Stop the watchdog of the running command whenever the script exits, including through `set -e`
+        lines.push(String::from("__drone_teleport_watchdog="));
+        lines.push(String::from(
+            "trap '[ -n \"$__drone_teleport_watchdog\" ] && kill -KILL \"$__drone_teleport_watchdog\" 2>/dev/null' EXIT",
+        ));
+    }
+
+    for (index, command) in commands.iter().enumerate() {
+        lines.push(marker(&index.to_string(), ""));
+
+        if let Some((seconds, grace)) = deadline {
+            // The watchdog gives up as soon as the script is gone, e.g. because it was terminated, so it doesn't hold the
+            // output open. Once the timeout is reported, it lets go of the output for the grace period as well.
+            lines.push(format!(
+                "( trap '' TERM; __drone_teleport_waited=0; while [ \"$__drone_teleport_waited\" -lt {} ] && kill -0 $$ 2>/dev/null; do sleep 1; __drone_teleport_waited=$((__drone_teleport_waited + 1)); done; kill -0 $$ 2>/dev/null || exit 0; {}; exec >/dev/null 2>&1; kill -TERM -$$; sleep {}; kill -KILL -$$ ) &",
+                seconds,
+                marker(MARKER_TIMEOUT, " >&2"),
+                grace
+            ));
+            lines.push(String::from("__drone_teleport_watchdog=$!"));
+        }
+
+        lines.push(command.to_string());
+
+        // `set -e` doesn't apply to every command (e.g. `false && true`), so check each status explicitly
+        lines.push(String::from("__drone_teleport_status=$?"));
+        if deadline.is_some() {
+            lines.push(String::from(
+                "kill -KILL \"$__drone_teleport_watchdog\" 2>/dev/null || true; __drone_teleport_watchdog=",
+            ));
+        }
+        lines.push(String::from(
+            "[ \"$__drone_teleport_status\" -eq 0 ] || exit \"$__drone_teleport_status\"",
+        ));
+    }
+
+    lines.join("\n")
+}
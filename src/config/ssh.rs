@@ -0,0 +1,503 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+use crate::config::{identity, shell, state::Config};
+
+// Host key algorithms Teleport nodes present their host certificates with, matching the ssh_config tbot renders
+const HOST_KEY_ALGORITHMS: &str = "ssh-ed25519-cert-v01@openssh.com,ecdsa-sha2-nistp256-cert-v01@openssh.com,ecdsa-sha2-nistp384-cert-v01@openssh.com,ecdsa-sha2-nistp521-cert-v01@openssh.com,rsa-sha2-512-cert-v01@openssh.com,rsa-sha2-256-cert-v01@openssh.com,ssh-rsa-cert-v01@openssh.com";
+
+/// The Machine ID key and certificate in data_path
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub key: PathBuf,
+    pub certificate: PathBuf,
+}
+
+/// Where the ssh options for every host come from
+#[derive(Debug, Clone)]
+pub enum Options {
+    /// Built from proxy, cluster and the identity in data_path
+    Generated(Identity),
+    /// An existing ssh_config, used as-is
+    File(PathBuf),
+}
+
+/// How the keys of hosts are verified
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyChecking {
+    /// Only accept hosts presenting a host certificate signed by the Teleport host CA
+    Strict,
+    /// Accept any host key
+    Accept,
+}
+
+/// Whether `cluster` may be used as a Teleport cluster name
+pub fn is_valid_cluster(cluster: &str) -> bool {
+    !cluster.is_empty()
+        && cluster
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+// Finds the Machine ID certificate in data_path, along with the private key it was issued for
+pub fn find_identity(data_path: &str) -> Option<Identity> {
+    let certificate = identity::find_certificate(data_path)?;
+    let name = certificate.file_name()?.to_string_lossy().to_string();
+    let key = certificate.with_file_name(name.strip_suffix("-cert.pub")?);
+
+    match key.is_file() {
+        true => Some(Identity { key, certificate }),
+        false => None,
+    }
+}
+
+// Decides where the ssh options come from. The ssh_config setting always wins. Otherwise the options are generated from the
+// identity in data_path, falling back to the ssh_config tbot renders into data_path when there is no identity to generate them from.
+pub fn get_options(cfg: &Config) -> Result<Options, io::Error> {
+    if let Some(path) = &cfg.ssh_config {
+        let path = PathBuf::from(path);
+        return match path.is_file() {
+            true => Ok(Options::File(path)),
+            false => Err(io::Error::other(format!(
+                "The ssh_config {} does not exist",
+                path.display()
+            ))),
+        };
+    }
+
+    if let Some(identity) = find_identity(&cfg.data_path) {
+        return Ok(Options::Generated(identity));
+    }
+
+    let path = Path::new(&cfg.data_path).join("ssh_config");
+    match path.is_file() {
+        true => Ok(Options::File(path)),
+        false => Err(io::Error::other(format!(
+            "No Machine ID identity (key and key-cert.pub) or ssh_config was found in {}. Check that data_path is the tbot destination directory, and that it is mounted into the step.",
+            cfg.data_path
+        ))),
+    }
+}
+
+// The file the Teleport host CA is read from, host_ca or the known_hosts tbot writes to data_path
+pub fn get_host_ca_path(cfg: &Config) -> PathBuf {
+    match &cfg.host_ca {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&cfg.data_path).join("known_hosts"),
+    }
+}
+
+// Reads the Teleport host CA keys as known_hosts lines. tbot limits its @cert-authority lines to the cluster's domain names,
+// but hosts are reached by their node names through the proxy, so every host is trusted when it has a certificate from the CA.
+// host_ca may also list the CA public keys on their own, as printed by `tctl auth export --type=host`.
+pub fn read_host_cas(cfg: &Config) -> Result<Vec<String>, io::Error> {
+    let path = get_host_ca_path(cfg);
+    let contents = std::fs::read_to_string(&path).map_err(|error| {
+        io::Error::other(format!(
+            "Unable to read the Teleport host CA from {}: {}. Set host_ca to a file with the host CA, or set host_key_checking to accept to trust any host key.",
+            path.display(),
+            error
+        ))
+    })?;
+
+    let mut lines = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            // Every OpenSSH public key starts with the length of its type, so the host patterns may be left out
+            ["@cert-authority", key_type, key, ..] if key.starts_with("AAAA") => {
+                lines.push(format!("@cert-authority * {} {}", key_type, key))
+            }
+            ["@cert-authority", _, key_type, key, ..] => {
+                lines.push(format!("@cert-authority * {} {}", key_type, key))
+            }
+            [key_type, key, ..] if cfg.host_ca.is_some() && !key_type.starts_with(['#', '@']) => {
+                lines.push(format!("@cert-authority * {} {}", key_type, key))
+            }
+            _ => {}
+        }
+    }
+
+    if lines.is_empty() {
+        return Err(io::Error::other(format!(
+            "No Teleport host CA (@cert-authority) was found in {}. Set host_ca to a file with the host CA, or set host_key_checking to accept to trust any host key.",
+            path.display()
+        )));
+    }
+
+    Ok(lines)
+}
+
+// Creates the directory generated ssh files are written to, which is deleted when it is dropped
+pub fn create_dir() -> Result<TempDir, io::Error> {
+    tempfile::Builder::new()
+        .prefix("drone-teleport-")
+        .tempdir()
+        .map_err(|error| {
+            io::Error::other(format!(
+                "Unable to create a directory for the generated ssh_config: {}",
+                error
+            ))
+        })
+}
+
+// Writes a file into `dir`, the directory for generated ssh files
+fn write_file(dir: &Path, name: &str, contents: &str) -> Result<PathBuf, io::Error> {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).map_err(|error| {
+        io::Error::other(format!("Unable to write {}: {}", path.display(), error))
+    })?;
+
+    Ok(path)
+}
+
+// Writes the known_hosts hosts are verified against into `dir`, or returns /dev/null when any host key is accepted
+pub fn write_known_hosts(cfg: &Config, dir: &Path) -> Result<PathBuf, io::Error> {
+    match cfg.host_key_checking {
+        HostKeyChecking::Strict => {
+            let mut lines = read_host_cas(cfg)?;
+            lines.push(String::new());
+            write_file(dir, "known_hosts", &lines.join("\n"))
+        }
+        HostKeyChecking::Accept => Ok(PathBuf::from("/dev/null")),
+    }
+}
+
+// Quotes a path for an ssh_config option
+fn quote_path(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
+
+// The command ssh runs to reach hosts through the Teleport proxy, in `cluster` or the proxy's own cluster
+fn proxy_command(cfg: &Config, cluster: Option<&str>) -> String {
+    let mut command = vec![
+        "tbot".to_string(),
+        "proxy".to_string(),
+        format!("--destination-dir={}", shell::quote(&cfg.data_path)),
+        format!("--proxy={}", shell::quote(&cfg.proxy)),
+        "ssh".to_string(),
+    ];
+
+    if let Some(cluster) = cluster {
+        command.push(format!("--cluster={}", shell::quote(cluster)));
+    }
+
+    command.push("%r@%h:%p".to_string());
+    command.join(" ")
+}
+
+// The ssh_config for hosts in a cluster, or in the proxy's own cluster when `cluster` is None
+pub fn render(
+    cfg: &Config,
+    options: &Options,
+    known_hosts: &Path,
+    cluster: Option<&str>,
+) -> String {
+    let mut lines = vec![
+        match cluster {
+            Some(cluster) => format!("# Generated by drone-teleport for the {} cluster", cluster),
+            None => "# Generated by drone-teleport for the proxy's cluster".to_string(),
+        },
+        "Host *".to_string(),
+    ];
+
+    // An existing ssh_config already routes hosts in the proxy's own cluster
+    if matches!(options, Options::Generated(_)) || cluster.is_some() {
+        lines.push(format!("    ProxyCommand {}", proxy_command(cfg, cluster)));
+    }
+
+    // Host keys are only checked against the generated known_hosts, even when an existing ssh_config sets its own
+    lines.push(format!(
+        "    UserKnownHostsFile {}",
+        quote_path(known_hosts)
+    ));
+    lines.push("    GlobalKnownHostsFile /dev/null".to_string());
+
+    match options {
+        Options::Generated(identity) => {
+            lines.push(format!("    IdentityFile {}", quote_path(&identity.key)));
+            lines.push(format!(
+                "    CertificateFile {}",
+                quote_path(&identity.certificate)
+            ));
+            lines.push(format!("    HostKeyAlgorithms {}", HOST_KEY_ALGORITHMS));
+        }
+        // Everything else comes from the existing ssh_config
+        Options::File(path) => lines.push(format!("Include {}", quote_path(path))),
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+// Writes the ssh_config for every cluster hosts are in into `dir`, returning the path of each by cluster
+pub fn write_configs(
+    cfg: &Config,
+    options: &Options,
+    dir: &Path,
+    clusters: &[Option<&str>],
+) -> Result<Vec<(Option<String>, PathBuf)>, io::Error> {
+    let known_hosts = write_known_hosts(cfg, dir)?;
+    let mut paths = Vec::new();
+
+    for cluster in clusters {
+        let name = match cluster {
+            Some(cluster) => format!("{}.ssh_config", cluster),
+            None => "ssh_config".to_string(),
+        };
+        let path = write_file(dir, &name, &render(cfg, options, &known_hosts, *cluster))?;
+        paths.push((cluster.map(str::to_string), path));
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIHq5X2pq1TzC2rJzIhA7nS0bUwQn9J7D4C0bVn3iP0cS";
+    const RSA_KEY: &str = "AAAAB3NzaC1yc2EAAAADAQABAAABAQC7y8nV2p";
+
+    // The settings to connect with, reading the host CA from `host_ca` when it is set, or else from data_path
+    fn config(data_path: &Path, host_ca: Option<&Path>) -> Config {
+        let mut argv = vec![
+            "drone-teleport".to_string(),
+            "--username=bot".to_string(),
+            "--proxy=teleport.example.com:443".to_string(),
+            format!("--data-path={}", data_path.display()),
+            "--hosts=web".to_string(),
+        ];
+        if let Some(host_ca) = host_ca {
+            argv.push(format!("--host-ca={}", host_ca.display()));
+        }
+        argv.push("connect".to_string());
+        Config::try_parse_from(argv).unwrap()
+    }
+
+    // Reads the host CA from a known_hosts in data_path, as tbot writes it
+    fn read_known_hosts(contents: &str) -> Result<Vec<String>, io::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("known_hosts"), contents).unwrap();
+        read_host_cas(&config(dir.path(), None))
+    }
+
+    // Reads the host CA from a host_ca file
+    fn read_host_ca(contents: &str) -> Result<Vec<String>, io::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("host_ca");
+        std::fs::write(&path, contents).unwrap();
+        read_host_cas(&config(dir.path(), Some(&path)))
+    }
+
+    #[test]
+    fn trusts_every_host_for_a_cert_authority_with_host_patterns() {
+        let contents = format!(
+            "@cert-authority teleport.example.com,*.teleport.example.com ssh-ed25519 {} type=host\n",
+            ED25519_KEY
+        );
+
+        assert_eq!(
+            read_known_hosts(&contents).unwrap(),
+            [format!("@cert-authority * ssh-ed25519 {}", ED25519_KEY)]
+        );
+    }
+
+    #[test]
+    fn trusts_every_host_for_a_cert_authority_without_host_patterns() {
+        let contents = format!("@cert-authority ssh-ed25519 {}\n", ED25519_KEY);
+
+        assert_eq!(
+            read_known_hosts(&contents).unwrap(),
+            [format!("@cert-authority * ssh-ed25519 {}", ED25519_KEY)]
+        );
+    }
+
+    #[test]
+    fn trusts_bare_keys_in_host_ca() {
+        let contents = format!(
+            "ssh-ed25519 {} clustername=teleport.example.com&type=host\nssh-rsa {}\n",
+            ED25519_KEY, RSA_KEY
+        );
+
+        assert_eq!(
+            read_host_ca(&contents).unwrap(),
+            [
+                format!("@cert-authority * ssh-ed25519 {}", ED25519_KEY),
+                format!("@cert-authority * ssh-rsa {}", RSA_KEY),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_bare_keys_in_the_known_hosts_of_data_path() {
+        // tbot's known_hosts may list plain host keys, which must not be trusted as a CA
+        let contents = format!(
+            "web.teleport.example.com ssh-ed25519 {}\n@cert-authority *.teleport.example.com ssh-rsa {}\n",
+            ED25519_KEY, RSA_KEY
+        );
+
+        assert_eq!(
+            read_known_hosts(&contents).unwrap(),
+            [format!("@cert-authority * ssh-rsa {}", RSA_KEY)]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let contents = format!(
+            "# Teleport host CA\n\n   \n  # ssh-rsa {}\n@cert-authority * ssh-ed25519 {}\n\n",
+            RSA_KEY, ED25519_KEY
+        );
+
+        assert_eq!(
+            read_host_ca(&contents).unwrap(),
+            [format!("@cert-authority * ssh-ed25519 {}", ED25519_KEY)]
+        );
+    }
+
+    #[test]
+    fn fails_without_a_cert_authority() {
+        let error = read_known_hosts("# nothing to trust\n\n").unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("No Teleport host CA (@cert-authority) was found in"));
+    }
+
+    #[test]
+    fn fails_without_a_host_ca_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = read_host_cas(&config(dir.path(), None)).unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("Unable to read the Teleport host CA from"));
+    }
+
+    #[test]
+    fn writes_the_cert_authorities_to_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("known_hosts"),
+            format!(
+                "@cert-authority teleport.example.com ssh-ed25519 {}\n@cert-authority teleport.example.com ssh-rsa {}\n",
+                ED25519_KEY, RSA_KEY
+            ),
+        )
+        .unwrap();
+        let output = tempfile::tempdir().unwrap();
+
+        let path = write_known_hosts(&config(dir.path(), None), output.path()).unwrap();
+
+        assert_eq!(path, output.path().join("known_hosts"));
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            format!(
+                "@cert-authority * ssh-ed25519 {}\n@cert-authority * ssh-rsa {}\n",
+                ED25519_KEY, RSA_KEY
+            )
+        );
+    }
+
+    // The ssh options generated from the identity in /opt/teleport/home
+    fn identity() -> Options {
+        Options::Generated(Identity {
+            key: PathBuf::from("/opt/teleport/home/key"),
+            certificate: PathBuf::from("/opt/teleport/home/key-cert.pub"),
+        })
+    }
+
+    #[test]
+    fn renders_the_ssh_config_of_a_host_in_another_cluster() {
+        // The host@cluster suffix overrides the cluster setting
+        let mut cfg = config(Path::new("/opt/teleport/home"), None);
+        cfg.cluster = Some("root.example.com".to_string());
+        let cluster = cfg.get_cluster("web@leaf.example.com");
+        let known_hosts = Path::new("/tmp/drone-teleport-x/known_hosts");
+
+        assert_eq!(cluster, Some("leaf.example.com"));
+        assert_eq!(cfg.get_cluster("web"), Some("root.example.com"));
+        assert_eq!(
+            render(&cfg, &identity(), known_hosts, cluster),
+            format!(
+                "# Generated by drone-teleport for the leaf.example.com cluster
+Host *
+    ProxyCommand tbot proxy --destination-dir=/opt/teleport/home --proxy=teleport.example.com:443 ssh --cluster=leaf.example.com %r@%h:%p
+    UserKnownHostsFile \"/tmp/drone-teleport-x/known_hosts\"
+    GlobalKnownHostsFile /dev/null
+    IdentityFile \"/opt/teleport/home/key\"
+    CertificateFile \"/opt/teleport/home/key-cert.pub\"
+    HostKeyAlgorithms {}
+",
+                HOST_KEY_ALGORITHMS
+            )
+        );
+    }
+
+    #[test]
+    fn renders_the_ssh_config_of_a_host_in_the_proxy_cluster() {
+        let cfg = config(Path::new("/opt/teleport/home"), None);
+        let cluster = cfg.get_cluster("web");
+        let known_hosts = Path::new("/tmp/drone-teleport-x/known_hosts");
+
+        assert_eq!(cluster, None);
+        assert_eq!(
+            render(&cfg, &identity(), known_hosts, cluster),
+            format!(
+                "# Generated by drone-teleport for the proxy's cluster
+Host *
+    ProxyCommand tbot proxy --destination-dir=/opt/teleport/home --proxy=teleport.example.com:443 ssh %r@%h:%p
+    UserKnownHostsFile \"/tmp/drone-teleport-x/known_hosts\"
+    GlobalKnownHostsFile /dev/null
+    IdentityFile \"/opt/teleport/home/key\"
+    CertificateFile \"/opt/teleport/home/key-cert.pub\"
+    HostKeyAlgorithms {}
+",
+                HOST_KEY_ALGORITHMS
+            )
+        );
+    }
+
+    #[test]
+    fn routes_only_other_clusters_through_an_existing_ssh_config() {
+        let cfg = config(Path::new("/opt/teleport/home"), None);
+        let options = Options::File(PathBuf::from("/opt/teleport/home/ssh_config"));
+        let known_hosts = Path::new("/dev/null");
+
+        assert_eq!(
+            render(&cfg, &options, known_hosts, None),
+            "# Generated by drone-teleport for the proxy's cluster
+Host *
+    UserKnownHostsFile \"/dev/null\"
+    GlobalKnownHostsFile /dev/null
+Include \"/opt/teleport/home/ssh_config\"
+"
+        );
+        assert_eq!(
+            render(&cfg, &options, known_hosts, Some("leaf.example.com")),
+            "# Generated by drone-teleport for the leaf.example.com cluster
+Host *
+    ProxyCommand tbot proxy --destination-dir=/opt/teleport/home --proxy=teleport.example.com:443 ssh --cluster=leaf.example.com %r@%h:%p
+    UserKnownHostsFile \"/dev/null\"
+    GlobalKnownHostsFile /dev/null
+Include \"/opt/teleport/home/ssh_config\"
+"
+        );
+    }
+
+    #[test]
+    fn quotes_the_proxy_command_arguments() {
+        let cfg = config(Path::new("/opt/teleport/my home"), None);
+
+        assert_eq!(
+            proxy_command(&cfg, Some("leaf")),
+            "tbot proxy --destination-dir='/opt/teleport/my home' --proxy=teleport.example.com:443 ssh --cluster=leaf %r@%h:%p"
+        );
+    }
+}
@@ -1,8 +1,25 @@
-use clap::Parser;
-use openssh::SessionBuilder;
-use std::sync::Arc;
+use clap::{CommandFactory, Parser};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tempfile::TempDir;
 
-use crate::config::{connect::ConnectConfig, transfer::TransferConfig};
+use crate::{
+    config::{
+        connect::ConnectConfig,
+        fetch::FetchConfig,
+        identity::{self, Certificate},
+        nodes, report,
+        rollout::{BatchSize, FailurePolicy},
+        ssh::{self, HostKeyChecking},
+        transfer::TransferConfig,
+    },
+    connection::{split_host, ConnectionBuilder},
+    error::Error,
+};
 
 #[derive(clap::Subcommand, Debug, Clone)]
 pub enum SubCommand {
@@ -10,6 +27,8 @@ pub enum SubCommand {
     Connect(ConnectConfig),
     /// Transfer a file to a Teleport host
     Transfer(TransferConfig),
+    /// Fetch files from a Teleport host
+    Fetch(FetchConfig),
 }
 
 /// A Drone CI plugin to execute commands on a remote host through Teleport Machine ID
@@ -28,27 +47,65 @@ pub struct Config {
     #[clap(
         long,
         value_parser,
-        required = true,
+        required_unless_present_any = &["host-labels", "host-query"],
         multiple_occurrences = true,
         use_value_delimiter = true,
         env = "PLUGIN_HOSTS"
     )]
     pub hosts: Vec<String>,
 
+    /// Teleport node labels to select hosts by, in addition to hosts, e.g. env=prod,role=web
+    #[clap(long, value_parser, env = "PLUGIN_HOST_LABELS")]
+    pub host_labels: Option<String>,
+
+    /// A Teleport predicate query to select hosts by, in addition to hosts, e.g. labels["env"] == "prod"
+    #[clap(long, value_parser, env = "PLUGIN_HOST_QUERY")]
+    pub host_query: Option<String>,
+
+    /// A command that prints nodes in the same format as `tsh ls --format=json`, run instead of tsh to resolve host_labels and host_query
+    #[clap(long, value_parser, env = "PLUGIN_HOST_QUERY_COMMAND")]
+    pub host_query_command: Option<String>,
+
+    /// The labels of every host that was resolved from Teleport
+    #[clap(skip)]
+    pub host_node_labels: BTreeMap<String, BTreeMap<String, String>>,
+
     ///  Teleport Proxy Endpoint (with port)
     #[clap(long, value_parser, required = true, env = "PLUGIN_PROXY")]
     pub proxy: String,
 
-    /// Teleport Cluster to connect to (unused)
+    /// The Teleport cluster hosts are in, e.g. a leaf cluster. Defaults to the cluster of the proxy. Hosts listed as host@cluster override it.
+    #[clap(short, long, value_parser, env = "PLUGIN_CLUSTER")]
+    pub cluster: Option<String>,
+
+    /// An existing ssh_config to connect with, instead of generating the ssh options from proxy, cluster and data_path
+    #[clap(long, value_parser, env = "PLUGIN_SSH_CONFIG")]
+    pub ssh_config: Option<String>,
+
+    /// How host keys are verified: strict, against the Teleport host CA, or accept, trusting any host key
     #[clap(
-        short,
         long,
-        value_parser,
-        required = false,
-        default_value = "",
-        env = "PROXY_CLUSTER"
+        value_enum,
+        default_value_t = HostKeyChecking::Strict,
+        env = "PLUGIN_HOST_KEY_CHECKING"
     )]
-    pub cluster: String,
+    pub host_key_checking: HostKeyChecking,
+
+    /// A file with the Teleport host CA to verify host keys against. Defaults to the known_hosts in data_path
+    #[clap(long, value_parser, env = "PLUGIN_HOST_CA")]
+    pub host_ca: Option<String>,
+
+    /// The ssh_config to connect to hosts in each cluster with, or in the proxy's own cluster for None
+    #[clap(skip)]
+    pub ssh_configs: BTreeMap<Option<String>, PathBuf>,
+
+    /// The directory the generated ssh_configs are written to, which is deleted once the last clone of the config is dropped
+    #[clap(skip)]
+    pub ssh_dir: Option<Arc<TempDir>>,
+
+    /// The environment of the runner, which env_passthrough, custom plugin settings and `{{ env.NAME }}` are read from
+    #[clap(skip = get_runner_env())]
+    pub runner_env: BTreeMap<String, String>,
 
     /// The teleport SSH port to use
     #[clap(short, long, value_parser, default_value_t = 3022, env = "PLUGIN_PORT")]
@@ -65,11 +122,41 @@ pub struct Config {
     )]
     pub data_path: String,
 
+    /// Whether to check the Machine ID certificate in data_path before connecting. Defaults to true.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = true,
+        env = "PLUGIN_IDENTITY_CHECK"
+    )]
+    pub identity_check: bool,
+
+    /// The number of minutes the Machine ID certificate must remain valid for
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5,
+        env = "PLUGIN_IDENTITY_MIN_VALIDITY"
+    )]
+    pub identity_min_validity: u64,
+
+    /// A file to write a JSON report of the run to
+    #[clap(long, value_parser, env = "PLUGIN_REPORT_PATH")]
+    pub report_path: Option<String>,
+
+    /// When the run started
+    #[clap(skip = SystemTime::now())]
+    pub started: SystemTime,
+
     /// Whether to enable debug mode or not
     #[clap(long, value_parser, default_value_t = false, env = "PLUGIN_DEBUG")]
     pub debug: bool,
 
-    /// The timeout for any single command
+    /// Print the hosts, commands and files of the run, then exit without connecting to any host
+    #[clap(long, value_parser, default_value_t = false, env = "PLUGIN_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// The timeout in seconds for any single command. Set to 0 to disable.
     #[clap(
         short,
         long,
@@ -77,34 +164,289 @@ pub struct Config {
         default_value_t = 120,
         env = "PLUGIN_TIMEOUT"
     )]
-    pub timeout: i32,
+    pub timeout: u64,
+
+    /// The timeout in seconds for establishing a connection to a Teleport host
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 30,
+        env = "PLUGIN_CONNECT_TIMEOUT"
+    )]
+    pub connect_timeout: u64,
+
+    /// The number of times to retry connecting to a host after a transient connection failure
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 2,
+        env = "PLUGIN_CONNECT_RETRIES"
+    )]
+    pub connect_retries: u32,
+
+    /// The number of seconds to wait before the first connection retry
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 2.0,
+        env = "PLUGIN_CONNECT_RETRY_DELAY"
+    )]
+    pub connect_retry_delay: f64,
+
+    /// The factor the retry delay is multiplied by after every retry
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 2.0,
+        env = "PLUGIN_CONNECT_RETRY_BACKOFF"
+    )]
+    pub connect_retry_backoff: f64,
+
+    /// The fraction of the retry delay to randomly add or remove, so hosts don't retry in lockstep
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0.2,
+        env = "PLUGIN_CONNECT_RETRY_JITTER"
+    )]
+    pub connect_retry_jitter: f64,
+
+    /// The number of hosts to run on at the same time, either as a number of hosts (2) or a percentage (25%). Defaults to all hosts.
+    #[clap(
+        long,
+        default_value = "100%",
+        parse(try_from_str = BatchSize::parse),
+        env = "PLUGIN_BATCH_SIZE"
+    )]
+    pub batch_size: BatchSize,
+
+    /// The number of seconds to wait between batches
+    #[clap(long, value_parser, default_value_t = 0, env = "PLUGIN_BATCH_PAUSE")]
+    pub batch_pause: u64,
+
+    /// Whether to stop the rollout when any host in a batch fails. Defaults to true.
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = true,
+        env = "PLUGIN_BATCH_STOP_ON_FAILURE"
+    )]
+    pub batch_stop_on_failure: bool,
+
+    /// What to do with the other hosts once a host fails: fail-fast or run-all
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = FailurePolicy::FailFast,
+        env = "PLUGIN_FAILURE_POLICY"
+    )]
+    pub failure_policy: FailurePolicy,
 }
 
 impl Config {
-    // Helper function to get the SessionBuilder configuration
-    pub fn get_sb<'a>(&'a self) -> SessionBuilder {
-        let mut sb = SessionBuilder::default();
-        sb.port(self.port)
-            .user(self.username.to_string())
-            .config_file(format!("{}/ssh_config", self.data_path))
-            .known_hosts_check(openssh::KnownHosts::Accept)
-            .compression(true);
-
-        return sb;
+    // The name of the operation being run
+    pub fn get_operation(&self) -> &'static str {
+        match self.cmd {
+            SubCommand::Connect(_) => "connect",
+            SubCommand::Transfer(_) => "transfer",
+            SubCommand::Fetch(_) => "fetch",
+        }
+    }
+
+    // Builds the connection to hosts, with the ssh_config of every cluster hosts are in
+    pub fn get_connection_builder(&self) -> ConnectionBuilder {
+        let mut connection = ConnectionBuilder::new(&self.username);
+        connection
+            .port(self.port)
+            .cluster(self.cluster.as_deref())
+            .known_hosts(match self.host_key_checking {
+                HostKeyChecking::Strict => openssh::KnownHosts::Strict,
+                HostKeyChecking::Accept => openssh::KnownHosts::Accept,
+            })
+            .host_ca(ssh::get_host_ca_path(self))
+            .connect_timeout(self.get_connect_timeout())
+            .retries(
+                self.connect_retries,
+                self.connect_retry_delay,
+                self.connect_retry_backoff,
+                self.connect_retry_jitter,
+            );
+
+        for (cluster, path) in self.ssh_configs.iter() {
+            connection.ssh_config(cluster.as_deref(), path.to_owned());
+        }
+
+        connection
+    }
+
+    // The cluster a host is in, from its host@cluster suffix or the cluster setting
+    pub fn get_cluster<'a>(&'a self, host: &'a str) -> Option<&'a str> {
+        split_host(host).1.or(self.cluster.as_deref())
+    }
+
+    // The deadline for establishing a connection to a single host
+    pub fn get_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    // Checks that the Machine ID identity in data_path is usable, before connecting to any host
+    pub fn validate_identity(&self) -> Result<(), std::io::Error> {
+        let path = match identity::find_certificate(&self.data_path) {
+            Some(path) => path,
+            None => {
+                return Err(std::io::Error::other(format!(
+                    "No SSH certificate (key-cert.pub) was found in {}. Check that tbot has written the identity to data_path.",
+                    self.data_path
+                )))
+            }
+        };
+
+        let certificate = Certificate::load(&path)?;
+        if self.debug {
+            println!(
+                "Machine ID certificate {}: {} ({}), principals: {}, extensions: {}, {}",
+                path.display(),
+                certificate.key_id,
+                certificate.key_type,
+                certificate.principals.join(", "),
+                certificate.extensions.join(", "),
+                certificate.describe_expiry()
+            );
+        }
+
+        certificate.validate(
+            &self.username,
+            Duration::from_secs(self.identity_min_validity * 60),
+        )
+    }
+
+    // The deadline for a single command, or None if commands may run indefinitely
+    pub fn get_command_timeout(&self) -> Option<Duration> {
+        match self.timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+}
+
+// The environment the plugin runs in, leaving out variables that aren't valid unicode
+pub fn get_runner_env() -> BTreeMap<String, String> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+// The names of the environment variables the plugin reads its own settings from, e.g. PLUGIN_SCRIPT
+pub fn get_setting_envs() -> HashSet<String> {
+    let command = Config::command();
+    let mut names = HashSet::new();
+
+    for cmd in std::iter::once(&command).chain(command.get_subcommands()) {
+        for arg in cmd.get_arguments() {
+            if let Some(name) = arg.get_env() {
+                names.insert(name.to_string_lossy().to_string());
+            }
+        }
     }
+
+    // PLUGIN_OP isn't an argument, it is passed as the subcommand by the ENTRYPOINT of the Docker image
+    names.insert(String::from("PLUGIN_OP"));
+
+    names
 }
 
 // Parsing command for clap to correctly build the configuration.
-pub fn get_config() -> Arc<Config> {
+pub fn get_config() -> Result<Arc<Config>, Error> {
     // Collect the arguments, then properly mutate the configuration with the parse_script_json so we can read the data from PLUGIN_SCRIPT correctly.
     // Find a way to do this with clap instead of here so args can be immutable
     let mut argsc = Config::parse();
 
-    if argsc.cluster == "" {
-        argsc.cluster = argsc.proxy.clone();
+    if argsc.identity_check {
+        if let Err(error) = argsc.validate_identity() {
+            return Err(report::abort(&argsc, Error::Config(error.to_string())));
+        }
+    }
+
+    // Add the hosts matching host_labels and host_query to the explicitly listed hosts
+    if argsc.host_labels.is_some() || argsc.host_query.is_some() {
+        match nodes::resolve(&argsc) {
+            Ok(nodes) => {
+                for (host, labels) in nodes {
+                    argsc.hosts.push(host.to_string());
+                    argsc.host_node_labels.insert(host, labels);
+                }
+            }
+            Err(error) => {
+                return Err(report::abort(&argsc, Error::Config(error.to_string())));
+            }
+        }
+    }
+
+    // Hosts that are both listed and matched, or listed twice, are only processed once
+    let mut seen = HashSet::new();
+    argsc.hosts.retain(|host| seen.insert(host.to_owned()));
+
+    // Every host connects with the ssh_config of its cluster
+    let mut clusters: Vec<Option<&str>> = argsc
+        .hosts
+        .iter()
+        .map(|host| argsc.get_cluster(host))
+        .collect();
+    clusters.sort();
+    clusters.dedup();
+
+    if let Some(cluster) = clusters
+        .iter()
+        .flatten()
+        .find(|cluster| !ssh::is_valid_cluster(cluster))
+    {
+        return Err(report::abort(
+            &argsc,
+            Error::Config(format!(
+                "Invalid Teleport cluster `{}`. Cluster names may only contain letters, digits, `-`, `_` and `.`",
+                cluster
+            )),
+        ));
+    }
+
+    // A dry run doesn't connect, so nothing is written for it
+    let options = match ssh::get_options(&argsc) {
+        Ok(_) if argsc.dry_run => None,
+        Ok(options) => Some(options),
+        Err(error) => return Err(report::abort(&argsc, Error::Config(error.to_string()))),
+    };
+
+    let written = match options {
+        Some(options) => ssh::create_dir().and_then(|dir| {
+            let paths = ssh::write_configs(&argsc, &options, dir.path(), &clusters)?;
+            Ok(Some((dir, paths)))
+        }),
+        None => Ok(None),
+    };
+    match written {
+        Ok(None) => {}
+        Ok(Some((dir, paths))) => {
+            argsc.ssh_dir = Some(Arc::new(dir));
+            for (cluster, path) in paths {
+                if argsc.debug {
+                    println!(
+                        "Connecting to hosts in {} with {}",
+                        match &cluster {
+                            Some(cluster) => format!("the {} cluster", cluster),
+                            None => "the proxy's cluster".to_string(),
+                        },
+                        path.display()
+                    );
+                }
+                argsc.ssh_configs.insert(cluster, path);
+            }
+        }
+        Err(error) => {
+            return Err(report::abort(&argsc, Error::Config(error.to_string())));
+        }
     }
 
     let args: Config = argsc.clone();
     drop(argsc);
-    return Arc::new(args);
+    Ok(Arc::new(args))
 }
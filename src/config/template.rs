@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    config::shell::{self, Quoting},
+    error::Error,
+};
+
+/// The values available to `{{ name }}` templates in scripts and transfer paths, for a single host
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// The host name, as it appears in `hosts`
+    pub host: String,
+    /// The position of the host in `hosts`, starting at 0
+    pub host_index: usize,
+    /// The batch the host runs in, starting at 1
+    pub batch: usize,
+    /// The Teleport node labels of the host, when known
+    pub labels: BTreeMap<String, String>,
+    /// The environment of the runner
+    pub env: BTreeMap<String, String>,
+}
+
+impl Context {
+    // Whether `name` is one of the variables templates may reference, whether or not it has a value
+    fn is_variable(name: &str) -> bool {
+        matches!(name, "host" | "host_index" | "batch")
+            || name.starts_with("env.")
+            || name.starts_with("labels.")
+    }
+
+    // Whether `name` is shaped like a variable, e.g. `host` or `labels.teleport.dev/origin`, rather than e.g. a Go
+    // template such as `.Names` or a Jinja expression such as `item | upper`
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || "_.-/".contains(c))
+    }
+
+    // Resolves a variable name to its value, or None if the variable doesn't exist
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name {
+            "host" => Some(self.host.to_string()),
+            "host_index" => Some(self.host_index.to_string()),
+            "batch" => Some(self.batch.to_string()),
+            _ => {
+                if let Some(var) = name.strip_prefix("env.") {
+                    return self.env.get(var).cloned();
+                }
+
+                if let Some(label) = name.strip_prefix("labels.") {
+                    return self.labels.get(label).cloned();
+                }
+
+                None
+            }
+        }
+    }
+
+    // Replaces every `{{ name }}` in `template` with its value, e.g. in a transfer path. Anything between `{{` and `}}`
+    // that isn't shaped like a variable, such as a Go template, is left as it is. An unknown variable, or a variable
+    // without a value, is an error.
+    pub fn render(&self, template: &str) -> Result<String, Error> {
+        self.render_with(template, false)
+    }
+
+    // Renders a command of a script like `render`, quoting each value for the quotes it appears in, so the shell of the
+    // host reads it as literal text
+    pub fn render_command(&self, template: &str) -> Result<String, Error> {
+        self.render_with(template, true)
+    }
+
+    fn render_with(&self, template: &str, command: bool) -> Result<String, Error> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut quoting = Quoting::None;
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => break,
+            };
+
+            let name = rest[start + 2..end].trim();
+            let literal = match Context::is_identifier(name) {
+                true => &rest[..start],
+                false => &rest[..start + 2],
+            };
+            rendered.push_str(literal);
+            quoting = shell::quoting_after(literal, quoting);
+            if !Context::is_identifier(name) {
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            if !Context::is_variable(name) {
+                return Err(Error::Config(format!(
+                    "Unknown template variable `{}` in `{}`. Variables are host, host_index, batch, env.NAME and labels.NAME",
+                    name, template
+                )));
+            }
+
+            match self.lookup(name) {
+                Some(value) if command => rendered.push_str(&shell::quote_within(&value, quoting)),
+                Some(value) => rendered.push_str(&value),
+                None => {
+                    return Err(Error::Config(format!(
+                        "Template variable `{}` in `{}` has no value for {}",
+                        name, template, self.host
+                    )))
+                }
+            }
+
+            rest = &rest[end + 2..];
+        }
+
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
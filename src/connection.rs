@@ -0,0 +1,405 @@
+use openssh::{KnownHosts, Session, SessionBuilder};
+use rand::Rng;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use tokio::time::timeout;
+
+use crate::{
+    error::Error,
+    output::{Event, Output},
+};
+
+// The longest connection retries wait for, however far the backoff has grown
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Connects to Teleport hosts with the ssh_config of the cluster each host is in, retrying transient failures
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    username: String,
+    port: u16,
+    cluster: Option<String>,
+    ssh_configs: BTreeMap<Option<String>, PathBuf>,
+    known_hosts: KnownHosts,
+    host_ca: Option<PathBuf>,
+    connect_timeout: Duration,
+    retries: u32,
+    retry_delay: f64,
+    retry_backoff: f64,
+    retry_jitter: f64,
+}
+
+impl ConnectionBuilder {
+    pub fn new(username: &str) -> ConnectionBuilder {
+        ConnectionBuilder {
+            username: username.to_string(),
+            port: 3022,
+            cluster: None,
+            ssh_configs: BTreeMap::new(),
+            known_hosts: KnownHosts::Strict,
+            host_ca: None,
+            connect_timeout: Duration::from_secs(30),
+            retries: 2,
+            retry_delay: 2.0,
+            retry_backoff: 2.0,
+            retry_jitter: 0.2,
+        }
+    }
+
+    /// The Teleport SSH port. Defaults to 3022.
+    pub fn port(&mut self, port: u16) -> &mut Self {
+        self.port = port;
+        self
+    }
+
+    /// The cluster of hosts that aren't listed as host@cluster. Defaults to the cluster of the proxy.
+    pub fn cluster(&mut self, cluster: Option<&str>) -> &mut Self {
+        self.cluster = cluster.map(str::to_string);
+        self
+    }
+
+    /// The ssh_config to connect to hosts in `cluster` with, or in the proxy's own cluster for None
+    pub fn ssh_config(&mut self, cluster: Option<&str>, path: PathBuf) -> &mut Self {
+        self.ssh_configs.insert(cluster.map(str::to_string), path);
+        self
+    }
+
+    /// How host keys are checked. Defaults to strict.
+    pub fn known_hosts(&mut self, known_hosts: KnownHosts) -> &mut Self {
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    /// The file the Teleport host CA was read from, to explain host key failures
+    pub fn host_ca(&mut self, path: PathBuf) -> &mut Self {
+        self.host_ca = Some(path);
+        self
+    }
+
+    /// The deadline for establishing a connection to a single host. Defaults to 30 seconds.
+    pub fn connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How often transient failures are retried, how long to wait before the first retry in seconds, the factor the
+    /// delay is multiplied by after every retry, and the fraction of the delay that is randomly added or removed.
+    /// Retries never wait longer than 5 minutes.
+    pub fn retries(&mut self, retries: u32, delay: f64, backoff: f64, jitter: f64) -> &mut Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self.retry_backoff = backoff;
+        self.retry_jitter = jitter;
+        self
+    }
+
+    // The cluster a host is in, from its host@cluster suffix or the default cluster
+    pub fn get_cluster<'a>(&'a self, host: &'a str) -> Option<&'a str> {
+        split_host(host).1.or(self.cluster.as_deref())
+    }
+
+    // Helper function to get the SessionBuilder configuration for hosts in `cluster`
+    pub fn get_sb(&self, cluster: Option<&str>) -> SessionBuilder {
+        let mut sb = SessionBuilder::default();
+        sb.port(self.port)
+            .user(self.username.to_string())
+            .known_hosts_check(self.known_hosts.clone())
+            .connect_timeout(self.connect_timeout)
+            .compression(true);
+
+        if let Some(path) = self.ssh_configs.get(&cluster.map(str::to_string)) {
+            sb.config_file(path);
+        }
+
+        sb
+    }
+
+    // The delay before connection retry `attempt`, starting at 1. Delays too long for a Duration are capped as well.
+    pub fn get_retry_delay(&self, attempt: u32) -> Duration {
+        // No backoff grows a delay of zero, not even an infinite one
+        if self.retry_delay.is_nan() || self.retry_delay <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        let retries = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+        let delay = self.retry_delay * self.retry_backoff.max(1.0).powi(retries);
+        let jitter = self.retry_jitter.clamp(0.0, 1.0);
+        let factor = match jitter > 0.0 {
+            true => rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter),
+            false => 1.0,
+        };
+
+        Duration::try_from_secs_f64(delay * factor)
+            .unwrap_or(MAX_RETRY_DELAY)
+            .min(MAX_RETRY_DELAY)
+    }
+
+    // Connects to a host through Teleport, retrying transient failures (e.g. the proxy refusing or dropping the connection).
+    // Failures that can't be fixed by trying again, such as authentication failures, are returned immediately.
+    pub async fn connect(&self, host: &str, output: &dyn Output) -> Result<Session, Error> {
+        let sb = self.get_sb(self.get_cluster(host));
+        let destination = split_host(host).0;
+        let mut attempt = 0;
+
+        loop {
+            let error = match timeout(self.connect_timeout, sb.connect(destination)).await {
+                Ok(Ok(session)) => return Ok(session),
+                Ok(Err(error)) => match is_transient(&error) {
+                    true => Error::Connection(describe_error(&error)),
+                    false => return Err(self.classify(&error)),
+                },
+                Err(_) => Error::Connection(format!(
+                    "connection timed out after {} seconds",
+                    self.connect_timeout.as_secs()
+                )),
+            };
+
+            attempt += 1;
+            if attempt > self.retries {
+                return Err(error);
+            }
+
+            let delay = self.get_retry_delay(attempt);
+            output.event(
+                host,
+                Event::Retrying {
+                    error: &error,
+                    delay,
+                    attempt,
+                    retries: self.retries,
+                },
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Turns a connection failure that won't be retried into an error, explaining host key failures
+    fn classify(&self, error: &openssh::Error) -> Error {
+        let description = describe_error(error);
+        let message = description.to_lowercase();
+
+        if message.contains("host key verification failed") {
+            return Error::Auth(match &self.host_ca {
+                Some(path) => format!(
+                    "{}. The host did not present a host certificate signed by the Teleport host CA in {}",
+                    description,
+                    path.display()
+                ),
+                None => description,
+            });
+        }
+
+        match ["permission denied", "certificate", "access denied"]
+            .iter()
+            .any(|auth| message.contains(auth))
+        {
+            true => Error::Auth(description),
+            false => Error::Connection(description),
+        }
+    }
+}
+
+// Splits a host listed as host@cluster into its node name and cluster
+pub fn split_host(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once('@') {
+        Some((name, "")) => (name, None),
+        Some((name, cluster)) => (name, Some(cluster)),
+        None => (host, None),
+    }
+}
+
+// Whether a connection failure is a transport failure that may succeed when tried again.
+// ssh reports these as exit status 255, and openssh surfaces them as connect, master or disconnect errors.
+pub fn is_transient(error: &openssh::Error) -> bool {
+    // Failures caused by configuration or credentials fail the same way every time
+    const PERMANENT: [&str; 6] = [
+        "permission denied",
+        "host key verification failed",
+        "no such file or directory",
+        "bad configuration option",
+        "certificate",
+        "access denied",
+    ];
+
+    match error {
+        openssh::Error::Connect(_) | openssh::Error::Master(_) => {
+            let message = describe_error(error).to_lowercase();
+            !PERMANENT
+                .iter()
+                .any(|permanent| message.contains(permanent))
+        }
+        openssh::Error::Disconnected => true,
+        _ => false,
+    }
+}
+
+// Formats an error along with its sources, as openssh keeps the ssh error message in the source
+pub fn describe_error(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        description = format!("{}: {}", description, error);
+        source = error.source();
+    }
+
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    // A builder retrying with `delay`, `backoff` and `jitter`
+    fn builder(delay: f64, backoff: f64, jitter: f64) -> ConnectionBuilder {
+        let mut builder = ConnectionBuilder::new("bot");
+        builder.retries(5, delay, backoff, jitter);
+        builder
+    }
+
+    // A failure to connect, with the message ssh printed
+    fn connect_error(message: &str) -> openssh::Error {
+        openssh::Error::Connect(io::Error::other(message.to_string()))
+    }
+
+    #[test]
+    fn grows_the_retry_delay_by_the_backoff() {
+        let builder = builder(2.0, 3.0, 0.0);
+
+        assert_eq!(builder.get_retry_delay(1), Duration::from_secs(2));
+        assert_eq!(builder.get_retry_delay(2), Duration::from_secs(6));
+        assert_eq!(builder.get_retry_delay(3), Duration::from_secs(18));
+    }
+
+    #[test]
+    fn keeps_the_retry_delay_without_backoff() {
+        let builder = builder(1.5, 0.5, 0.0);
+
+        assert_eq!(builder.get_retry_delay(1), Duration::from_millis(1500));
+        assert_eq!(builder.get_retry_delay(4), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn keeps_the_jitter_within_its_fraction_of_the_delay() {
+        let builder = builder(10.0, 2.0, 0.2);
+
+        for _ in 0..1000 {
+            let delay = builder.get_retry_delay(2);
+            assert!(delay >= Duration::from_secs(16), "{:?} is too short", delay);
+            assert!(delay <= Duration::from_secs(24), "{:?} is too long", delay);
+        }
+    }
+
+    #[test]
+    fn limits_the_jitter_to_the_whole_delay() {
+        let builder = builder(10.0, 1.0, 5.0);
+
+        for _ in 0..1000 {
+            assert!(builder.get_retry_delay(1) <= Duration::from_secs(20));
+        }
+    }
+
+    #[test]
+    fn caps_the_retry_delay() {
+        assert_eq!(builder(2.0, 2.0, 0.2).get_retry_delay(20), MAX_RETRY_DELAY);
+        assert_eq!(builder(2.0, 1e300, 0.0).get_retry_delay(5), MAX_RETRY_DELAY);
+        assert_eq!(
+            builder(f64::INFINITY, 2.0, 0.0).get_retry_delay(1),
+            MAX_RETRY_DELAY
+        );
+        assert_eq!(
+            builder(2.0, 2.0, 0.0).get_retry_delay(u32::MAX),
+            MAX_RETRY_DELAY
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_retry_settings() {
+        assert_eq!(builder(-1.0, 2.0, 0.0).get_retry_delay(3), Duration::ZERO);
+        assert_eq!(
+            builder(f64::NAN, 2.0, 0.0).get_retry_delay(1),
+            Duration::ZERO
+        );
+        assert_eq!(
+            builder(0.0, f64::INFINITY, 0.0).get_retry_delay(3),
+            Duration::ZERO
+        );
+        assert_eq!(
+            builder(2.0, f64::NAN, f64::NAN).get_retry_delay(3),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn retries_transport_failures() {
+        assert!(is_transient(&connect_error(
+            "kex_exchange_identification: Connection closed by remote host"
+        )));
+        assert!(is_transient(&openssh::Error::Master(io::Error::other(
+            "Connection refused"
+        ))));
+        assert!(is_transient(&openssh::Error::Disconnected));
+    }
+
+    #[test]
+    fn does_not_retry_permanent_failures() {
+        for message in [
+            "bot@web: Permission denied (publickey).",
+            "Host key verification failed.",
+            "Can't open user config file /tmp/ssh_config: No such file or directory",
+            "/tmp/ssh_config: line 3: Bad configuration option: proxycomand",
+            "Certificate invalid: expired",
+            "ERROR: access denied to bot connecting to web",
+        ] {
+            assert!(
+                !is_transient(&connect_error(message)),
+                "{} was retried",
+                message
+            );
+        }
+        assert!(!is_transient(&openssh::Error::Remote(io::Error::other(
+            "Connection refused"
+        ))));
+    }
+
+    #[test]
+    fn classifies_authentication_failures() {
+        let builder = builder(2.0, 2.0, 0.0);
+
+        assert!(matches!(
+            builder.classify(&connect_error("bot@web: Permission denied (publickey).")),
+            Error::Auth(_)
+        ));
+        assert!(matches!(
+            builder.classify(&connect_error(
+                "ERROR: access denied to bot connecting to web"
+            )),
+            Error::Auth(_)
+        ));
+        assert!(matches!(
+            builder.classify(&connect_error("Could not resolve hostname web")),
+            Error::Connection(_)
+        ));
+    }
+
+    #[test]
+    fn explains_host_key_failures_with_the_host_ca() {
+        let error = connect_error("Host key verification failed.");
+        let mut builder = builder(2.0, 2.0, 0.0);
+
+        match builder.classify(&error) {
+            Error::Auth(message) => assert_eq!(
+                message,
+                "failed to connect to the remote host: Host key verification failed."
+            ),
+            error => panic!("unexpected error {:?}", error),
+        }
+
+        builder.host_ca(PathBuf::from("/opt/teleport/home/known_hosts"));
+        match builder.classify(&error) {
+            Error::Auth(message) => assert_eq!(
+                message,
+                "failed to connect to the remote host: Host key verification failed.. The host did not present a host certificate signed by the Teleport host CA in /opt/teleport/home/known_hosts"
+            ),
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+}
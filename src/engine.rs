@@ -0,0 +1,1246 @@
+extern crate tar;
+
+use futures::stream::{self, BoxStream, FuturesUnordered, StreamExt};
+use glob::{glob_with, MatchOptions};
+use rand::distributions::{Alphanumeric, DistString};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{remove_file, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tar::Builder;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc::{self, Sender},
+};
+
+use crate::{
+    config::{report::StepResult, rollout::Cancellation},
+    error::Error,
+    output::{Event, Output, Silent},
+    release::Release,
+    transport::{FileSystem, RemoteFile, RemoteSource, Transport},
+};
+
+/// The host that events about building archives are reported for, as archives are built on the machine running the transfer
+pub const LOCAL_HOST: &str = "localhost";
+
+/// Transfers files to a host by archiving them locally, uploading the archive over SFTP and extracting it on the host.
+/// Files are fetched from a host the other way around.
+#[derive(Debug, Clone)]
+pub struct TransferEngine {
+    compress: bool,
+    compress_level: i32,
+    stream: bool,
+    window: usize,
+    release: Option<Release>,
+}
+
+impl Default for TransferEngine {
+    fn default() -> TransferEngine {
+        TransferEngine::new()
+    }
+}
+
+impl TransferEngine {
+    // 128 KiB
+    const BUF_SIZE: usize = 128 * 1024;
+
+    // How far archiving may get ahead of the upload when streaming, in chunks of `BUF_SIZE`
+    const STREAM_CHUNKS: usize = 16;
+
+    // Progress is reported every 4 MiB
+    const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+    pub fn new() -> TransferEngine {
+        TransferEngine {
+            compress: true,
+            compress_level: 13,
+            stream: false,
+            window: 16,
+            release: None,
+        }
+    }
+
+    /// Whether archives are compressed with zstd before they are uploaded. Defaults to true.
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    /// The zstd compression level. Defaults to 13.
+    pub fn compress_level(&mut self, compress_level: i32) -> &mut Self {
+        self.compress_level = compress_level;
+        self
+    }
+
+    /// Whether archives are streamed to every host as they are built, instead of being built once in `/tmp` and
+    /// shared by all hosts. Streaming needs no local disk, but archives and compresses every src once per host.
+    /// Defaults to false.
+    pub fn stream(&mut self, stream: bool) -> &mut Self {
+        self.stream = stream;
+        self
+    }
+
+    /// How many writes to a remote file may be in flight at the same time. Over a high latency connection, every
+    /// write waits a full round trip for its acknowledgement, so a larger window keeps more of the connection busy.
+    /// Defaults to 16, and is at least 1.
+    pub fn window(&mut self, window: usize) -> &mut Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Deploys into a new release directory within every dst, instead of extracting over it. `dst/current` is only
+    /// switched to the release once every file was extracted on the host. Defaults to None.
+    pub fn release(&mut self, release: Option<Release>) -> &mut Self {
+        self.release = release;
+        self
+    }
+
+    /// Whether archives are streamed to every host as they are built
+    pub fn is_streaming(&self) -> bool {
+        self.stream
+    }
+
+    /// The release that is deployed, if files are deployed into release directories
+    pub fn get_release(&self) -> Option<&Release> {
+        self.release.as_ref()
+    }
+
+    /// Where the files for `dst` are extracted to on a host, which is the release directory when deploying releases
+    pub fn destination(&self, dst: &str) -> String {
+        match &self.release {
+            Some(release) => release.dir(dst),
+            None => dst.to_string(),
+        }
+    }
+
+    /// Works out what transferring `src` involves, without touching any host: the files matched by the `src` glob
+    /// and the archive they are packed into.
+    pub fn plan(&self, src: &str) -> Result<TransferPlan, Error> {
+        let glob_options = MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: false,
+            require_literal_leading_dot: false,
+        };
+
+        let glob = glob_with(src, glob_options).map_err(|e| Error::Transfer {
+            src: src.to_string(),
+            message: e.to_string(),
+        })?;
+        let files = glob
+            .flatten()
+            .map(|path| {
+                // Directories are added to the archive as an entry of their own, without their contents
+                let size = match std::fs::metadata(&path) {
+                    Ok(metadata) if metadata.is_file() => metadata.len(),
+                    _ => 0,
+                };
+                (path, size)
+            })
+            .collect();
+
+        let farcname = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let archive_name = match self.compress {
+            true => format!("{}.tar.zst", farcname),
+            false => format!("{}.tar", farcname),
+        };
+
+        Ok(TransferPlan {
+            files,
+            local_archive: format!("/tmp/{}.tar", farcname),
+            upload: format!("/tmp/{}", archive_name),
+            archive_name,
+        })
+    }
+
+    /// Builds the archive of everything matched by `src`, compressing it when compression is enabled. The archive
+    /// is deleted once it is dropped, so it can be uploaded to any number of hosts in the meantime.
+    /// Archiving is synchronous IO, so this should run on a blocking thread.
+    pub fn prepare(&self, src: &str, output: &dyn Output) -> Result<Archive, Error> {
+        let failed = |message: String| Error::Transfer {
+            src: src.to_string(),
+            message,
+        };
+        let plan = self.plan(src)?;
+
+        // Grab all the files matched by the glob, thenn create an archive to upload
+        output.event(LOCAL_HOST, Event::Archiving { src });
+
+        let tarfile = TempFile(plan.local_archive.clone());
+        let archive = File::create(&tarfile.0).map_err(|e| failed(e.to_string()))?;
+        let mut archive_builder = Builder::new(archive);
+
+        for (path, _) in plan.files.iter() {
+            if let Err(done) = archive_builder.append_path(path) {
+                return Err(failed(format!(
+                    "failed to add {}: {}",
+                    path.display(),
+                    done
+                )));
+            }
+        }
+
+        // Verify that the archive is built out
+        if let Err(done) = archive_builder.finish() {
+            return Err(failed(format!("unable to create local archive: {}", done)));
+        }
+        drop(archive_builder);
+        let size = std::fs::metadata(&tarfile.0).map(|m| m.len()).ok();
+
+        // If compression is enabled, compress to archive to zstd
+        let mut upload = tarfile;
+        if self.compress {
+            output.event(LOCAL_HOST, Event::Compressing { src });
+            let zstfile = TempFile(plan.upload.clone());
+            let compressed = File::create(&zstfile.0)
+                .and_then(|new_archive| zstd::Encoder::new(new_archive, self.compress_level))
+                .and_then(|mut encoder| {
+                    let mut archive = File::open(&upload.0)?;
+                    std::io::copy(&mut archive, &mut encoder)?;
+                    encoder.finish()
+                });
+
+            if let Err(done) = compressed {
+                return Err(failed(format!("compression failed: {}", done)));
+            }
+
+            // Replacing the upload deletes the uncompressed archive
+            upload = zstfile;
+        }
+
+        Ok(Archive {
+            src: src.to_string(),
+            name: plan.archive_name,
+            file: upload,
+            size,
+        })
+    }
+
+    /// Builds the archive of every `src` once, so it can be shared by all hosts
+    pub fn prepare_all<'a>(
+        &self,
+        srcs: impl IntoIterator<Item = &'a String>,
+        output: &dyn Output,
+    ) -> Archives {
+        let mut archives = Archives::new();
+        for src in srcs {
+            if !archives.contains_key(src) {
+                let archive = self.prepare(src, output).map(Arc::new);
+                archives.insert(src.to_string(), archive);
+            }
+        }
+
+        archives
+    }
+
+    // Creates `dst` and all of its parents on the remote, with 0775 permissions
+    async fn create_remote_dir(files: &dyn FileSystem<'_>, dst: &str) {
+        // sftp doesn't have a `mkdir -p` equivalent so we have to make them each one-by-one, in tree order
+        let mut paths: Vec<&Path> = Path::new(dst)
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty() && *path != Path::new("/"))
+            .collect();
+        paths.reverse();
+
+        for path in paths {
+            // The directory usually exists already
+            #[allow(unused_must_use)]
+            {
+                files.create_dir(&path.display().to_string()).await;
+            }
+        }
+    }
+
+    // Uploads the archive of `src` to `dst` and extracts it there, recording the sizes in `step`
+    #[allow(clippy::too_many_arguments)]
+    async fn transfer_file(
+        &self,
+        transport: &dyn Transport,
+        files: &dyn FileSystem<'_>,
+        host: &str,
+        archive: &Archive,
+        dst: &str,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> Result<(), Error> {
+        let src = archive.src.as_str();
+        let failed = |message: String| Error::Transfer {
+            src: src.to_string(),
+            message,
+        };
+
+        // Create dst on the remote server
+        output.event(host, Event::CreatingDirectory { src, dst });
+        TransferEngine::create_remote_dir(files, dst).await;
+        step.size = archive.size;
+
+        // Rewind the archive by re-opening the file
+        let farchive = tokio::fs::File::open(&archive.file.0)
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+        let size = farchive
+            .metadata()
+            .await
+            .map_err(|e| failed(e.to_string()))?
+            .len();
+
+        let chunks = stream::unfold(farchive, |mut farchive| async move {
+            let mut buffer = vec![0u8; TransferEngine::BUF_SIZE];
+            match farchive.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(rc) => {
+                    buffer.truncate(rc);
+                    Some((Ok(buffer), farchive))
+                }
+                Err(e) => Some((Err(e), farchive)),
+            }
+        });
+
+        let remote_archive = archive.remote_path(dst);
+        let uploaded = self
+            .upload(
+                files,
+                host,
+                src,
+                &remote_archive,
+                Some(size),
+                chunks.boxed(),
+                step,
+                output,
+            )
+            .await;
+        if let Err(message) = uploaded {
+            // Don't leave the incomplete archive behind on the remote
+            #[allow(unused_must_use)]
+            {
+                transport.output(cleanup(&archive.name, dst)).await;
+            }
+            return Err(failed(message));
+        }
+
+        TransferEngine::extract(transport, host, src, &archive.name, dst, output).await
+    }
+
+    // Archives, compresses and uploads `src` to `dst` at the same time, without writing anything to local disk,
+    // then extracts it there. The archive is built on a blocking thread, and handed to the upload in chunks
+    // through a bounded channel, so archiving never gets more than `STREAM_CHUNKS` chunks ahead of the upload.
+    #[allow(clippy::too_many_arguments)]
+    async fn stream_file(
+        &self,
+        transport: &dyn Transport,
+        files: &dyn FileSystem<'_>,
+        host: &str,
+        src: &str,
+        dst: &str,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> Result<(), Error> {
+        let failed = |message: String| Error::Transfer {
+            src: src.to_string(),
+            message,
+        };
+        let plan = self.plan(src)?;
+
+        // Create dst on the remote server
+        output.event(host, Event::CreatingDirectory { src, dst });
+        TransferEngine::create_remote_dir(files, dst).await;
+
+        output.event(host, Event::Archiving { src });
+        if self.compress {
+            output.event(host, Event::Compressing { src });
+        }
+
+        let (sender, receiver) = mpsc::channel(TransferEngine::STREAM_CHUNKS);
+        let producer = {
+            let engine = self.clone();
+            let files = plan.files.clone();
+            tokio::task::spawn_blocking(move || engine.write_stream(&files, sender))
+        };
+
+        // The receiver is dropped once the upload stops, which stops the producer if the upload failed
+        let chunks = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (Ok(chunk), receiver))
+        });
+        let remote_archive = plan.remote_path(dst);
+        let uploaded = self
+            .upload(
+                files,
+                host,
+                src,
+                &remote_archive,
+                None,
+                chunks.boxed(),
+                step,
+                output,
+            )
+            .await;
+        let archived = producer
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("archiving stopped unexpectedly")));
+
+        let uploaded = match (uploaded, archived) {
+            (Err(message), _) => Err(message),
+            (Ok(()), Err(done)) => Err(format!("unable to create local archive: {}", done)),
+            (Ok(()), Ok(size)) => Ok(size),
+        };
+        match uploaded {
+            Ok(size) => step.size = Some(size),
+            Err(message) => {
+                // Don't leave the incomplete archive behind on the remote, whether the upload or archiving failed
+                #[allow(unused_must_use)]
+                {
+                    transport.output(cleanup(&plan.archive_name, dst)).await;
+                }
+                return Err(failed(message));
+            }
+        }
+
+        TransferEngine::extract(transport, host, src, &plan.archive_name, dst, output).await
+    }
+
+    // Archives `files` into chunks sent to `sender`, compressing them when compression is enabled.
+    // Returns the size of the uncompressed archive. This blocks, so it has to run on a blocking thread.
+    fn write_stream(&self, files: &[(PathBuf, u64)], sender: Sender<Vec<u8>>) -> io::Result<u64> {
+        let chunks = ChunkWriter::new(sender);
+        if self.compress {
+            let encoder = zstd::Encoder::new(chunks, self.compress_level)?;
+            let counter = write_archive(files, Counter::new(encoder))?;
+            let size = counter.count;
+            counter.inner.finish()?.flush()?;
+            Ok(size)
+        } else {
+            let mut counter = write_archive(files, Counter::new(chunks))?;
+            counter.flush()?;
+            Ok(counter.count)
+        }
+    }
+
+    // Writes every chunk to `path` on the remote, reporting progress as it goes and recording the bytes written in `step`.
+    // `size` is the total size of the upload, if it is known up front.
+    #[allow(clippy::too_many_arguments)]
+    async fn upload(
+        &self,
+        files: &dyn FileSystem<'_>,
+        host: &str,
+        src: &str,
+        path: &str,
+        size: Option<u64>,
+        chunks: BoxStream<'_, io::Result<Vec<u8>>>,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> Result<(), String> {
+        // Create the remote archive file on the SFTP server
+        let r_file = files
+            .create(path)
+            .await
+            .map_err(|e| format!("unable to create remote file: {}", e))?;
+
+        output.event(host, Event::Uploading { src, path, size });
+
+        let now = Instant::now();
+        let written = self
+            .write_chunks(r_file.as_ref(), host, src, size, chunks, step, output)
+            .await;
+
+        // Close the remote file
+        #[allow(unused_must_use)]
+        {
+            r_file.close().await;
+        }
+        written.map_err(|e| format!("upload failed: {}", e))?;
+
+        output.event(
+            host,
+            Event::Uploaded {
+                src,
+                size: step.bytes.unwrap_or_default(),
+                elapsed: now.elapsed(),
+            },
+        );
+
+        Ok(())
+    }
+
+    // Writes every chunk to `file` in order, keeping up to `window` writes in flight so the upload isn't held up
+    // by the round trip of every single write
+    #[allow(clippy::too_many_arguments)]
+    async fn write_chunks(
+        &self,
+        file: &dyn RemoteFile<'_>,
+        host: &str,
+        src: &str,
+        size: Option<u64>,
+        mut chunks: BoxStream<'_, io::Result<Vec<u8>>>,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> io::Result<()> {
+        let mut writes = FuturesUnordered::new();
+        let mut offset = 0;
+        let mut uploaded = 0;
+        let mut next_progress = TransferEngine::PROGRESS_INTERVAL;
+        let mut archived = false;
+        step.bytes = Some(0);
+
+        loop {
+            tokio::select! {
+                chunk = chunks.next(), if !archived && writes.len() < self.window => match chunk {
+                    Some(chunk) => {
+                        let chunk = chunk?;
+                        let len = chunk.len() as u64;
+                        writes.push(async move { file.write_at(offset, &chunk).await.map(|()| len) });
+                        offset += len;
+                    }
+                    None => archived = true,
+                },
+                Some(written) = writes.next() => {
+                    uploaded += written?;
+                    step.bytes = Some(uploaded);
+
+                    // Report progress every 4 MiB
+                    if uploaded >= next_progress {
+                        output.event(
+                            host,
+                            Event::Progress {
+                                src,
+                                transferred: uploaded,
+                                size,
+                            },
+                        );
+                        next_progress += TransferEngine::PROGRESS_INTERVAL;
+                    }
+                },
+                else => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Extracts the uploaded archive `name` into `dst` on the remote, then deletes it
+    async fn extract(
+        transport: &dyn Transport,
+        host: &str,
+        src: &str,
+        name: &str,
+        dst: &str,
+        output: &dyn Output,
+    ) -> Result<(), Error> {
+        // Extract the archive on the remote server and delete it
+        output.event(host, Event::Extracting { archive: name, dst });
+
+        let extracted = match transport.output(extract(name, dst)).await {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(format!(
+                "unable to extract archive (exit {}): {}",
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => Err(format!("unable to extract archive: {}", e)),
+        };
+
+        output.event(host, Event::Cleaning { archive: name });
+
+        // Delete the archive on the remote
+        if let Err(_command) = transport.output(cleanup(name, dst)).await {
+            output.event(host, Event::Warning("Unable to delete archive on remote"));
+        }
+
+        extracted.map_err(|message| Error::Extraction {
+            src: src.to_string(),
+            message,
+        })
+    }
+
+    // Transfers every file to the host over SFTP, stopping at the first failure and recording each file in `steps`.
+    // The archives built by `prepare_all` are reused, any other src is archived for this host alone.
+    // When streaming, every src is archived for this host while it is uploaded instead.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn transfer(
+        &self,
+        transport: &dyn Transport,
+        host: &str,
+        files: &HashMap<String, String>,
+        archives: &Archives,
+        cancellation: &Cancellation,
+        output: &dyn Output,
+        steps: &mut Vec<StepResult>,
+    ) -> Result<(), Error> {
+        let sftp = transport.files().await.map_err(|error| Error::Execution {
+            command: None,
+            message: error.to_string(),
+        })?;
+
+        // Every dst gets a release directory of its own, which doesn't exist until it is prepared
+        let dsts: BTreeSet<&str> = files.values().map(String::as_str).collect();
+        let mut prepared = Vec::new();
+        let mut outcome = Ok(());
+        if let Some(release) = &self.release {
+            for dst in dsts {
+                match run_release(transport, dst, release.prepare(dst)).await {
+                    Ok(_) => prepared.push(dst),
+                    Err(error) => {
+                        outcome = Err(error);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (src, dst) in files.iter() {
+            // Another host failed, so don't start any new transfers
+            if cancellation.is_cancelled() {
+                outcome = Err(Error::Cancelled);
+            }
+            if outcome.is_err() {
+                break;
+            }
+
+            let dst = &self.destination(dst);
+            let mut step = StepResult::new(src);
+            let started = Instant::now();
+            let transferred = if self.stream {
+                self.stream_file(transport, sftp.as_ref(), host, src, dst, &mut step, output)
+                    .await
+            } else {
+                let archive = match archives.get(src) {
+                    Some(archive) => archive.clone(),
+                    None => self.prepare_blocking(src).await,
+                };
+                match archive {
+                    Ok(archive) => {
+                        self.transfer_file(
+                            transport,
+                            sftp.as_ref(),
+                            host,
+                            &archive,
+                            dst,
+                            &mut step,
+                            output,
+                        )
+                        .await
+                    }
+                    Err(error) => Err(error),
+                }
+            };
+            step.duration = started.elapsed();
+            step.error = transferred.as_ref().err().map(|error| error.to_string());
+            steps.push(step);
+
+            if transferred.is_err() {
+                outcome = transferred;
+            }
+        }
+
+        // Close the sftp connection
+        #[allow(unused_must_use)]
+        {
+            sftp.close().await;
+        }
+
+        if let Some(release) = &self.release {
+            if outcome.is_ok() {
+                outcome = Self::activate(transport, host, release, &mut prepared, output).await;
+            }
+
+            // A release that wasn't switched to is deleted, leaving `dst/current` as it was
+            for dst in prepared {
+                if run_release(transport, dst, release.discard(dst))
+                    .await
+                    .is_err()
+                {
+                    output.event(
+                        host,
+                        Event::Warning("Unable to delete failed release on remote"),
+                    );
+                }
+            }
+        }
+
+        outcome
+    }
+
+    // Switches `dst/current` to the release within every one of the `prepared` dsts, and prunes their older releases.
+    // Nothing is switched until the shared paths of every dst were copied, and the dsts that were already switched are
+    // switched back when a later one fails, so the host never runs a mix of releases. Every dst that still points at
+    // the release when this returns is removed from `prepared`.
+    async fn activate(
+        transport: &dyn Transport,
+        host: &str,
+        release: &Release,
+        prepared: &mut Vec<&str>,
+        output: &dyn Output,
+    ) -> Result<(), Error> {
+        // Remember the release every dst points at, so it can be switched back to
+        let mut previous = Vec::new();
+        for &dst in prepared.iter() {
+            if let Some(share) = release.share(dst) {
+                run_release(transport, dst, share).await?;
+            }
+
+            let current = run_release(transport, dst, release.current(dst)).await?;
+            previous.push(Some(current).filter(|current| !current.is_empty()));
+        }
+
+        let mut failed = None;
+        for (index, &dst) in prepared.iter().enumerate() {
+            output.event(
+                host,
+                Event::Releasing {
+                    dst,
+                    name: &release.name,
+                },
+            );
+            if let Err(error) = run_release(transport, dst, release.switch(dst)).await {
+                failed = Some((index, error));
+                break;
+            }
+        }
+
+        if let Some((switched, error)) = failed {
+            for index in (0..switched).rev() {
+                let dst = prepared[index];
+                let restore = release.restore(dst, previous[index].as_deref());
+                if run_release(transport, dst, restore).await.is_err() {
+                    output.event(
+                        host,
+                        Event::Warning("Unable to switch back to the previous release on remote"),
+                    );
+
+                    // `current` still points at the release, so it must not be deleted
+                    prepared.remove(index);
+                }
+            }
+
+            return Err(error);
+        }
+
+        for dst in prepared.drain(..) {
+            if let Some(prune) = release.prune(dst) {
+                output.event(
+                    host,
+                    Event::Pruning {
+                        dst,
+                        keep: release.keep,
+                    },
+                );
+                if run_release(transport, dst, prune).await.is_err() {
+                    output.event(
+                        host,
+                        Event::Warning("Unable to delete old releases on remote"),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The command that archives everything matched by the remote glob `src` into `path` on a host,
+    /// compressed when compression is enabled. The glob is expanded by the shell of the host.
+    pub fn fetch_command(&self, src: &str, path: &str) -> String {
+        match self.compress {
+            true => format!(
+                "tar -I 'zstd -{}' -cf {} -- {}",
+                self.compress_level, path, src
+            ),
+            false => format!("tar -cf {} -- {}", path, src),
+        }
+    }
+
+    /// The name of the archive `src` is packed into on the host, which is random for every fetch. It is relative to
+    /// the home directory, like the archives uploaded by a transfer, so the archive command and SFTP agree on where it is.
+    pub fn fetch_name(&self) -> String {
+        let farcname = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        match self.compress {
+            true => format!(".drone-teleport-{}.tar.zst", farcname),
+            false => format!(".drone-teleport-{}.tar", farcname),
+        }
+    }
+
+    // Archives everything matched by `src` on the host, downloads the archive and extracts it into `dst`,
+    // recording the sizes in `step`
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_file(
+        &self,
+        transport: &dyn Transport,
+        files: &dyn FileSystem<'_>,
+        host: &str,
+        src: &str,
+        dst: &Path,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> Result<(), Error> {
+        let failed = |message: String| Error::Transfer {
+            src: src.to_string(),
+            message,
+        };
+
+        // Archive the files on the host
+        output.event(host, Event::Archiving { src });
+        let remote_archive = self.fetch_name();
+        let archived = match transport
+            .output(self.fetch_command(src, &remote_archive))
+            .await
+        {
+            Ok(command) if command.status.success() => Ok(()),
+            // tar exits with 1 when files changed while they were read, which is expected of e.g. logs being written.
+            // The archive is complete, so it is still downloaded.
+            Ok(command) if command.status.code() == Some(1) => {
+                let message = format!(
+                    "Files changed while they were archived: {}",
+                    String::from_utf8_lossy(&command.stderr).trim()
+                );
+                output.event(host, Event::Warning(&message));
+                Ok(())
+            }
+            Ok(command) => Err(format!(
+                "unable to create remote archive (exit {}): {}",
+                command.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&command.stderr).trim()
+            )),
+            Err(e) => Err(format!("unable to create remote archive: {}", e)),
+        };
+
+        // Download it, then delete it from the host whether the download worked or not. A failed tar may have
+        // left part of an archive behind, so it is deleted then as well.
+        let local = TempFile(format!("/tmp/{}", self.fetch_name()));
+        let downloaded = match archived {
+            Ok(()) => {
+                self.download(files, host, src, &remote_archive, &local.0, step, output)
+                    .await
+            }
+            Err(message) => Err(message),
+        };
+
+        output.event(
+            host,
+            Event::Cleaning {
+                archive: &remote_archive,
+            },
+        );
+        match transport.output(format!("rm -f {}", remote_archive)).await {
+            Ok(removed) if removed.status.success() => {}
+            _ => output.event(host, Event::Warning("Unable to delete archive on remote")),
+        }
+        downloaded.map_err(failed)?;
+
+        // Extract the archive locally
+        let dst_display = dst.display().to_string();
+        output.event(
+            host,
+            Event::Extracting {
+                archive: &remote_archive,
+                dst: &dst_display,
+            },
+        );
+
+        let compressed = self.compress;
+        let dst = dst.to_path_buf();
+        tokio::task::spawn_blocking(move || unpack(&local.0, &dst, compressed))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|unpacked| unpacked)
+            .map_err(|e| Error::Extraction {
+                src: src.to_string(),
+                message: format!("unable to extract archive: {}", e),
+            })
+    }
+
+    // Downloads `path` from the host to the local file `local`, keeping up to `window` reads in flight.
+    // Reports progress as it goes and records the bytes downloaded in `step`.
+    #[allow(clippy::too_many_arguments)]
+    async fn download(
+        &self,
+        files: &dyn FileSystem<'_>,
+        host: &str,
+        src: &str,
+        path: &str,
+        local: &str,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> Result<(), String> {
+        let source = files
+            .open(path)
+            .await
+            .map_err(|e| format!("unable to open remote archive: {}", e))?;
+        let size = source.size();
+        step.size = size;
+
+        output.event(host, Event::Downloading { src, path, size });
+
+        let now = Instant::now();
+        let read = self
+            .read_chunks(source.as_ref(), host, src, local, step, output)
+            .await;
+
+        // Close the remote file
+        #[allow(unused_must_use)]
+        {
+            source.close().await;
+        }
+        read.map_err(|e| format!("download failed: {}", e))?;
+
+        output.event(
+            host,
+            Event::Downloaded {
+                src,
+                size: step.bytes.unwrap_or_default(),
+                elapsed: now.elapsed(),
+            },
+        );
+
+        Ok(())
+    }
+
+    // Reads `source` into the local file `local`. Reads complete in any order, so the chunks that arrive early
+    // are held until every chunk before them was written.
+    #[allow(clippy::too_many_arguments)]
+    async fn read_chunks(
+        &self,
+        source: &dyn RemoteSource<'_>,
+        host: &str,
+        src: &str,
+        local: &str,
+        step: &mut StepResult,
+        output: &dyn Output,
+    ) -> io::Result<()> {
+        let mut file = tokio::fs::File::create(local).await?;
+        let mut reads = FuturesUnordered::new();
+        let mut arrived: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut offset = 0;
+        let mut downloaded = 0;
+        let mut next_progress = TransferEngine::PROGRESS_INTERVAL;
+        let mut ended = false;
+        step.bytes = Some(0);
+
+        loop {
+            while !ended && reads.len() < self.window {
+                let at = offset;
+                reads.push(async move {
+                    source
+                        .read_at(at, TransferEngine::BUF_SIZE)
+                        .await
+                        .map(|chunk| (at, chunk))
+                });
+                offset += TransferEngine::BUF_SIZE as u64;
+            }
+
+            let (at, chunk) = match reads.next().await {
+                Some(read) => read?,
+                None => break,
+            };
+
+            // A short read is the end of the file, so nothing after it has to be read
+            if chunk.len() < TransferEngine::BUF_SIZE {
+                ended = true;
+            }
+            arrived.insert(at, chunk);
+
+            while let Some(chunk) = arrived.remove(&downloaded) {
+                if chunk.is_empty() {
+                    break;
+                }
+                file.write_all(&chunk).await?;
+                downloaded += chunk.len() as u64;
+                step.bytes = Some(downloaded);
+
+                // Report progress every 4 MiB
+                if downloaded >= next_progress {
+                    output.event(
+                        host,
+                        Event::Progress {
+                            src,
+                            transferred: downloaded,
+                            size: step.size,
+                        },
+                    );
+                    next_progress += TransferEngine::PROGRESS_INTERVAL;
+                }
+            }
+        }
+
+        file.flush().await
+    }
+
+    // Fetches every remote src from the host into its local dst, in a subdirectory named after the host so files
+    // fetched from different hosts don't overwrite each other. Stops at the first failure, recording each src in `steps`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn fetch(
+        &self,
+        transport: &dyn Transport,
+        host: &str,
+        files: &HashMap<String, String>,
+        cancellation: &Cancellation,
+        output: &dyn Output,
+        steps: &mut Vec<StepResult>,
+    ) -> Result<(), Error> {
+        let sftp = transport.files().await.map_err(|error| Error::Execution {
+            command: None,
+            message: error.to_string(),
+        })?;
+
+        let mut outcome = Ok(());
+        for (src, dst) in files.iter() {
+            // Another host failed, so don't start any new transfers
+            if cancellation.is_cancelled() {
+                outcome = Err(Error::Cancelled);
+                break;
+            }
+
+            let mut step = StepResult::new(src);
+            let started = Instant::now();
+            let fetched = self
+                .fetch_file(
+                    transport,
+                    sftp.as_ref(),
+                    host,
+                    src,
+                    &Path::new(dst).join(host),
+                    &mut step,
+                    output,
+                )
+                .await;
+            step.duration = started.elapsed();
+            step.error = fetched.as_ref().err().map(|error| error.to_string());
+            steps.push(step);
+
+            if fetched.is_err() {
+                outcome = fetched;
+                break;
+            }
+        }
+
+        // Close the sftp connection
+        #[allow(unused_must_use)]
+        {
+            sftp.close().await;
+        }
+
+        outcome
+    }
+
+    // Builds the archive of a src that wasn't prepared up front, on a blocking thread
+    async fn prepare_blocking(&self, src: &str) -> Result<Arc<Archive>, Error> {
+        let engine = self.clone();
+        let owned = src.to_string();
+        tokio::task::spawn_blocking(move || engine.prepare(&owned, &Silent))
+            .await
+            .map_err(|error| Error::Transfer {
+                src: src.to_string(),
+                message: error.to_string(),
+            })?
+            .map(Arc::new)
+    }
+}
+
+/// How a single `src` is transferred to a host
+#[derive(Debug, Clone)]
+pub struct TransferPlan {
+    /// Every path matched by the `src` glob, with its size in bytes
+    pub files: Vec<(PathBuf, u64)>,
+    /// The name of the archive, which is random for every transfer
+    pub archive_name: String,
+    /// Where the tar archive is built locally
+    pub local_archive: String,
+    /// The local file that is uploaded, which is the compressed archive when compression is enabled
+    pub upload: String,
+}
+
+impl TransferPlan {
+    /// The combined size of every file matched by `src`
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Where the archive is uploaded to on a host
+    pub fn remote_path(&self, dst: &str) -> String {
+        remote_path(&self.archive_name, dst)
+    }
+
+    /// The command that extracts the archive on a host
+    pub fn extract(&self, dst: &str) -> String {
+        extract(&self.archive_name, dst)
+    }
+}
+
+/// The archive of a `src`, built once and uploaded to every host
+#[derive(Debug)]
+pub struct Archive {
+    /// The glob the archive was built from
+    pub src: String,
+    /// The file name of the archive, which is the same on every host
+    pub name: String,
+    /// The size of the uncompressed archive
+    pub size: Option<u64>,
+    file: TempFile,
+}
+
+impl Archive {
+    /// The local path of the archive that is uploaded
+    pub fn path(&self) -> &str {
+        &self.file.0
+    }
+
+    /// Where the archive is uploaded to on a host
+    pub fn remote_path(&self, dst: &str) -> String {
+        remote_path(&self.name, dst)
+    }
+
+    /// The command that extracts the archive on a host
+    pub fn extract(&self, dst: &str) -> String {
+        extract(&self.name, dst)
+    }
+
+    /// The command that deletes the archive from a host once it is extracted
+    pub fn cleanup(&self, dst: &str) -> String {
+        cleanup(&self.name, dst)
+    }
+}
+
+/// The archive of every src, or why it couldn't be built. Each archive is deleted once the last host is done with it.
+pub type Archives = HashMap<String, Result<Arc<Archive>, Error>>;
+
+// Where the archive `name` is uploaded to within `dst`
+fn remote_path(name: &str, dst: &str) -> String {
+    format!("{}/{}", dst, name)
+}
+
+// The command that extracts the archive `name` within `dst`
+fn extract(name: &str, dst: &str) -> String {
+    format!("tar -xf {} -C {}", remote_path(name, dst), dst)
+}
+
+// The command that deletes the archive `name` from `dst`
+fn cleanup(name: &str, dst: &str) -> String {
+    format!("rm {}", remote_path(name, dst))
+}
+
+// Runs `command` for the release within `dst` on a host, returning what it printed
+async fn run_release(
+    transport: &dyn Transport,
+    dst: &str,
+    command: String,
+) -> Result<String, Error> {
+    let failed = |message: String| Error::Release {
+        dst: dst.to_string(),
+        message,
+    };
+
+    match transport.output(command.clone()).await {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(output) => Err(failed(format!(
+            "`{}` failed (exit {}): {}",
+            command,
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+        Err(e) => Err(failed(format!("`{}` failed: {}", command, e))),
+    }
+}
+
+// Extracts the local archive `path` into `dst`, creating it if needed
+fn unpack(path: &str, dst: &Path, compressed: bool) -> io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    let file = File::open(path)?;
+    match compressed {
+        true => tar::Archive::new(zstd::Decoder::new(file)?).unpack(dst),
+        false => tar::Archive::new(file).unpack(dst),
+    }
+}
+
+// Writes a tar archive of `files` to `writer`, returning the writer once the archive is complete
+fn write_archive<W: Write>(files: &[(PathBuf, u64)], writer: W) -> io::Result<W> {
+    let mut builder = Builder::new(writer);
+    for (path, _) in files {
+        builder.append_path(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to add {}: {}", path.display(), e))
+        })?;
+    }
+
+    builder.into_inner()
+}
+
+/// Hands everything written to it to a channel, in chunks of up to `TransferEngine::BUF_SIZE`
+struct ChunkWriter {
+    sender: Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(sender: Sender<Vec<u8>>) -> ChunkWriter {
+        ChunkWriter {
+            sender,
+            buffer: Vec::with_capacity(TransferEngine::BUF_SIZE),
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(TransferEngine::BUF_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == TransferEngine::BUF_SIZE {
+            self.flush()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        // Blocks while the channel is full, and fails once the upload stopped
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(TransferEngine::BUF_SIZE),
+        );
+        self.sender
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the upload stopped"))
+    }
+}
+
+/// Counts the bytes written through it
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Counter<W> {
+    fn new(inner: W) -> Counter<W> {
+        Counter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A local temporary file that is removed once it is no longer needed
+#[derive(Debug)]
+struct TempFile(String);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        #[allow(unused_must_use)]
+        {
+            remove_file(&self.0);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+/// An error from connecting to a host, running commands on it, or transferring files to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The settings are invalid, e.g. a template references an unknown variable
+    Config(String),
+    /// Unable to connect to the host
+    Connection(String),
+    /// The host rejected the Machine ID identity, or its host key couldn't be verified
+    Auth(String),
+    /// The connection was lost while a command was running
+    Disconnected {
+        command: Option<String>,
+        message: String,
+    },
+    /// A command couldn't be executed, e.g. because sudo requires a password
+    Execution {
+        command: Option<String>,
+        message: String,
+    },
+    /// A command exited with a non-zero status
+    CommandFailed {
+        command: String,
+        exit_code: Option<i32>,
+    },
+    /// A command ran for longer than the timeout
+    Timeout {
+        command: Option<String>,
+        seconds: u64,
+    },
+    /// Files couldn't be archived or uploaded
+    Transfer { src: String, message: String },
+    /// The uploaded archive couldn't be extracted on the host
+    Extraction { src: String, message: String },
+    /// The release couldn't be prepared or switched to within `dst` on the host
+    Release { dst: String, message: String },
+    /// The host stopped early because another host failed
+    Cancelled,
+}
+
+impl Error {
+    // The status code the plugin exits with when a host fails with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_)
+            | Error::CommandFailed { .. }
+            | Error::Transfer { .. }
+            | Error::Extraction { .. }
+            | Error::Release { .. }
+            | Error::Cancelled => 1,
+            Error::Execution { .. } => 2,
+            Error::Connection(_) | Error::Auth(_) | Error::Disconnected { .. } => 3,
+            Error::Timeout { .. } => 4,
+        }
+    }
+
+    // The command, or the src of the file, that failed
+    pub fn command(&self) -> Option<&str> {
+        match self {
+            Error::CommandFailed { command, .. } => Some(command),
+            Error::Disconnected { command, .. }
+            | Error::Execution { command, .. }
+            | Error::Timeout { command, .. } => command.as_deref(),
+            Error::Transfer { src, .. } | Error::Extraction { src, .. } => Some(src),
+            Error::Release { dst, .. } => Some(dst),
+            Error::Config(_) | Error::Connection(_) | Error::Auth(_) | Error::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Connection(message) | Error::Auth(message) => {
+                write!(f, "{}", message)
+            }
+            Error::Disconnected { message, .. }
+            | Error::Execution { message, .. }
+            | Error::Transfer { message, .. }
+            | Error::Extraction { message, .. }
+            | Error::Release { message, .. } => write!(f, "{}", message),
+            Error::CommandFailed { command, exit_code } => write!(
+                f,
+                "`{}` exited with status {}",
+                command,
+                exit_code.unwrap_or(-1)
+            ),
+            Error::Timeout { seconds, .. } => write!(f, "timed out after {} seconds", seconds),
+            Error::Cancelled => write!(f, "cancelled because another host failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
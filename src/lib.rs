@@ -0,0 +1,22 @@
+//! Runs commands on, and transfers files to, Teleport hosts through a Machine ID identity.
+//!
+//! Connections are made with a [`ConnectionBuilder`], commands are run with a [`CommandRunner`] and files are
+//! transferred with a [`TransferEngine`]. Commands and files go through a [`Transport`], so hosts can be faked locally
+//! with [`transport::LocalConnector`]. Each of them returns a typed [`Error`], and reports what happens on a host as
+//! [`Event`]s to an [`Output`].
+pub mod config;
+pub mod connection;
+pub mod engine;
+pub mod error;
+pub mod output;
+pub mod release;
+pub mod runner;
+pub mod transport;
+
+pub use connection::ConnectionBuilder;
+pub use engine::{Archive, Archives, TransferEngine, TransferPlan};
+pub use error::Error;
+pub use output::{Event, Output, Silent};
+pub use release::Release;
+pub use runner::{CommandRunner, ScriptMode};
+pub use transport::{Connector, Transport};
@@ -1,4 +1,5 @@
-mod config;
+use drone_teleport::config::state::{get_config, SubCommand};
+use std::process::exit;
 
 extern crate tokio;
 
@@ -6,14 +7,22 @@ extern crate tokio;
 #[tokio::main]
 async fn main() {
     // Parse arguments with clap => config::Config struct
-    let cfg = config::state::get_config();
+    let cfg = match get_config() {
+        Ok(cfg) => cfg,
+        Err(error) => exit(error.exit_code()),
+    };
 
-    match &cfg.cmd {
-        config::state::SubCommand::Connect(config) => {
-            config.connect(&cfg).await;
-        }
-        config::state::SubCommand::Transfer(config) => {
-            config.transfer(&cfg).await;
-        }
-    }
+    let result = match &cfg.cmd {
+        SubCommand::Connect(config) => config.connect(&cfg).await,
+        SubCommand::Transfer(config) => config.transfer(&cfg).await,
+        SubCommand::Fetch(config) => config.fetch(&cfg).await,
+    };
+    let code = match result {
+        Ok(code) => code,
+        Err(error) => error.exit_code(),
+    };
+
+    // exit doesn't run destructors, so the generated ssh_config is deleted first
+    drop(cfg);
+    exit(code);
 }
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Something that happened on a host, reported while connecting, running commands or transferring files
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// Connecting failed with a transient error, and is tried again after `delay`
+    Retrying {
+        error: &'a Error,
+        delay: Duration,
+        attempt: u32,
+        retries: u32,
+    },
+    /// A command started running
+    Command(&'a str),
+    /// A line of output from the running command
+    Line { line: &'a str, is_stderr: bool },
+    /// The remote directory files are uploaded to is being created
+    CreatingDirectory { src: &'a str, dst: &'a str },
+    /// The files matching `src` are being archived
+    Archiving { src: &'a str },
+    /// The archive of `src` is being compressed
+    Compressing { src: &'a str },
+    /// The archive of `src` is being uploaded to `path`. The size isn't known when the archive is streamed.
+    Uploading {
+        src: &'a str,
+        path: &'a str,
+        size: Option<u64>,
+    },
+    /// Part of the archive of `src` was uploaded
+    Progress {
+        src: &'a str,
+        transferred: u64,
+        size: Option<u64>,
+    },
+    /// The archive of `src` was uploaded
+    Uploaded {
+        src: &'a str,
+        size: u64,
+        elapsed: Duration,
+    },
+    /// The archive of `src` is being downloaded from `path` on the host
+    Downloading {
+        src: &'a str,
+        path: &'a str,
+        size: Option<u64>,
+    },
+    /// The archive of `src` was downloaded
+    Downloaded {
+        src: &'a str,
+        size: u64,
+        elapsed: Duration,
+    },
+    /// The uploaded archive is being extracted into `dst`
+    Extracting { archive: &'a str, dst: &'a str },
+    /// The uploaded archive is being removed
+    Cleaning { archive: &'a str },
+    /// `dst/current` is being switched to the release `name`, now that every file was extracted
+    Releasing { dst: &'a str, name: &'a str },
+    /// Releases within `dst` beyond the newest `keep` are being deleted
+    Pruning { dst: &'a str, keep: usize },
+    /// Something went wrong that doesn't fail the host
+    Warning(&'a str),
+}
+
+/// Receives events as they happen on each host, e.g. to print them
+pub trait Output: Send + Sync {
+    fn event(&self, host: &str, event: Event);
+}
+
+/// Discards every event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Output for Silent {
+    fn event(&self, _host: &str, _event: Event) {}
+}
@@ -0,0 +1,133 @@
+// Commands that deploy into release directories on a host, so `dst/current` only ever points at a complete release
+
+use crate::config::shell::quote;
+
+/// Deploys every file into a new release directory within `dst`, and switches the `dst/current` symlink to it once
+/// every file was extracted on the host
+#[derive(Debug, Clone)]
+pub struct Release {
+    /// The name of the release directory, e.g. the build number or commit sha
+    pub name: String,
+    /// Paths within the current release that are copied into the new release before it is switched to
+    pub shared: Vec<String>,
+    /// How many releases are kept on the host, including the new one. 0 keeps every release.
+    pub keep: usize,
+}
+
+impl Release {
+    pub fn new(name: &str) -> Release {
+        Release {
+            name: name.to_string(),
+            shared: Vec::new(),
+            keep: 5,
+        }
+    }
+
+    /// Whether `name` may be used as the name of a release directory
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    }
+
+    /// The directory of the release within `dst`, which files are extracted into
+    pub fn dir(&self, dst: &str) -> String {
+        format!("{}/releases/{}", dst, self.name)
+    }
+
+    /// The command that removes what is left of an earlier deploy of the same release, refusing to touch it
+    /// when it is the current release
+    pub fn prepare(&self, dst: &str) -> String {
+        let current = quote(&format!("{}/current", dst));
+        let target = quote(&format!("releases/{}", self.name));
+        format!(
+            "if [ \"$(readlink {})\" = {} ]; then echo {} >&2; exit 1; fi; rm -rf {}",
+            current,
+            target,
+            quote(&format!("release {} is the current release", self.name)),
+            quote(&self.dir(dst)),
+        )
+    }
+
+    /// The command that copies the shared paths of the current release into the new one, if there are any
+    pub fn share(&self, dst: &str) -> Option<String> {
+        if self.shared.is_empty() {
+            return None;
+        }
+
+        let dir = self.dir(dst);
+        let commands: Vec<String> = self
+            .shared
+            .iter()
+            .map(|path| {
+                let path = path.trim_matches('/');
+                let from = quote(&format!("{}/current/{}", dst, path));
+                let to = quote(&format!("{}/{}", dir, path));
+                // Paths that don't exist in the current release yet, e.g. on the first deploy, are skipped
+                format!(
+                    "if [ -e {0} ]; then rm -rf {1} && mkdir -p \"$(dirname {1})\" && cp -a {0} {1}; fi",
+                    from, to
+                )
+            })
+            .collect();
+
+        Some(commands.join(" && "))
+    }
+
+    /// The command that prints the release `dst/current` points at, e.g. `releases/r1`, or nothing when there is none
+    pub fn current(&self, dst: &str) -> String {
+        format!("readlink {} || true", quote(&format!("{}/current", dst)))
+    }
+
+    /// The command that atomically points `dst/current` at the release, by renaming a new symlink over it.
+    /// `mv -T` renames the symlink instead of moving it into the directory `current` points at, so it needs GNU coreutils.
+    pub fn switch(&self, dst: &str) -> String {
+        self.link(dst, &format!("releases/{}", self.name))
+    }
+
+    /// The command that points `dst/current` back at `previous`, as printed by `current` before the release was
+    /// switched to, or removes it when there was no release before
+    pub fn restore(&self, dst: &str, previous: Option<&str>) -> String {
+        match previous {
+            Some(previous) => self.link(dst, previous),
+            None => format!("rm -f {}", quote(&format!("{}/current", dst))),
+        }
+    }
+
+    // The command that atomically points `dst/current` at `target`
+    fn link(&self, dst: &str, target: &str) -> String {
+        let link = quote(&format!("{}/.current-{}", dst, self.name));
+        format!(
+            "ln -sfn {0} {1} && mv -Tf {1} {2}",
+            quote(target),
+            link,
+            quote(&format!("{}/current", dst)),
+        )
+    }
+
+    /// The command that deletes every release but the newest `keep`, or None when every release is kept
+    pub fn prune(&self, dst: &str) -> Option<String> {
+        if self.keep == 0 {
+            return None;
+        }
+
+        // The new release is always kept, even if another release was modified more recently
+        Some(format!(
+            "cd {} && ls -1t | grep -vxF {} | tail -n +{} | xargs -r rm -rf --",
+            quote(&format!("{}/releases", dst)),
+            quote(&self.name),
+            self.keep
+        ))
+    }
+
+    /// The command that deletes the release after a failed deploy, leaving `dst/current` as it was
+    pub fn discard(&self, dst: &str) -> String {
+        format!(
+            "rm -rf {} {}",
+            quote(&self.dir(dst)),
+            quote(&format!("{}/.current-{}", dst, self.name))
+        )
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    collections::BTreeMap,
+    process::ExitStatus,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    time::timeout,
+};
+
+use crate::{
+    config::{report::StepResult, rollout::Cancellation, shell},
+    error::Error,
+    output::{Event, Output},
+    transport::Transport,
+};
+
+/// How the commands in `script` are executed on each host
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptMode {
+    /// Run every command in a single shell, so `cd`, variables and functions carry over between commands
+    Script,
+    /// Run every command in a separate shell
+    Commands,
+}
+
+/// Tracks which command of a script is executing, from the markers the script prints
+#[derive(Debug)]
+struct ScriptProgress {
+    command: Option<usize>,
+    started: Instant,
+    timed_out: bool,
+    /// The process id of the script on the host, once it started
+    pid: Option<u32>,
+    /// Every command that exited successfully
+    finished: Vec<StepResult>,
+    /// Whether the output of the current command is kept
+    capture: bool,
+    stdout: String,
+    stderr: String,
+}
+
+impl ScriptProgress {
+    fn new(capture: bool) -> ScriptProgress {
+        ScriptProgress {
+            command: None,
+            started: Instant::now(),
+            timed_out: false,
+            pid: None,
+            finished: Vec::new(),
+            capture,
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+
+    // Keeps a line of output of the current command, when output is captured
+    fn capture(&mut self, line: &str, is_stderr: bool) {
+        if self.capture {
+            let output = match is_stderr {
+                true => &mut self.stderr,
+                false => &mut self.stdout,
+            };
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    // Records how long the current command took, and its output, in `step`
+    fn record(&mut self, step: &mut StepResult) {
+        step.duration = self.started.elapsed();
+        step.stdout = std::mem::take(&mut self.stdout);
+        step.stderr = std::mem::take(&mut self.stderr);
+    }
+}
+
+/// Runs commands on a host, streaming their output as it arrives and stopping at the first failure
+#[derive(Debug, Clone)]
+pub struct CommandRunner {
+    env: String,
+    mode: ScriptMode,
+    shell: String,
+    working_dir: Option<String>,
+    run_as: Option<String>,
+    timeout: Option<Duration>,
+    capture: bool,
+}
+
+impl Default for CommandRunner {
+    fn default() -> CommandRunner {
+        CommandRunner::new()
+    }
+}
+
+impl CommandRunner {
+    // Seconds a timed out command has to exit after SIGTERM before it is sent SIGKILL
+    pub const KILL_GRACE: u64 = 10;
+
+    pub fn new() -> CommandRunner {
+        CommandRunner {
+            env: String::new(),
+            mode: ScriptMode::Script,
+            shell: String::from("sh -e"),
+            working_dir: None,
+            run_as: None,
+            timeout: None,
+            capture: false,
+        }
+    }
+
+    /// The environment variables to export before running the commands
+    pub fn env(&mut self, vars: &BTreeMap<String, String>) -> &mut Self {
+        let exports: Vec<String> = vars
+            .iter()
+            .map(|(k, v)| format!("export {}={}", k, shell::quote(v)))
+            .collect();
+        self.env = exports.join(" && ");
+        self
+    }
+
+    /// Whether commands run as a single script, or each in its own shell. Defaults to a single script.
+    pub fn mode(&mut self, mode: ScriptMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The shell, with options, that runs the script in script mode. Defaults to `sh -e`.
+    pub fn shell(&mut self, shell: &str) -> &mut Self {
+        self.shell = shell.to_string();
+        self
+    }
+
+    /// The directory on the remote to run commands from
+    pub fn working_dir(&mut self, working_dir: Option<&str>) -> &mut Self {
+        self.working_dir = working_dir.map(str::to_string);
+        self
+    }
+
+    /// The user to run commands as, through non-interactive sudo
+    pub fn run_as(&mut self, run_as: Option<&str>) -> &mut Self {
+        self.run_as = run_as.map(str::to_string);
+        self
+    }
+
+    /// The deadline for a single command, or None if commands may run indefinitely
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the output of every command is kept in its step
+    pub fn capture(&mut self, capture: bool) -> &mut Self {
+        self.capture = capture;
+        self
+    }
+
+    // Wraps a remote command so it runs from working_dir, as the run_as user, when they are set
+    pub fn wrap_command(&self, command: String) -> String {
+        let mut command = command;
+        if let Some(dir) = &self.working_dir {
+            command = format!("cd {} && {}", shell::quote(dir), command);
+        }
+
+        if let Some(user) = &self.run_as {
+            command = format!(
+                "sudo -n -u {} -- sh -c {}",
+                shell::quote(user),
+                shell::quote(&command)
+            );
+        }
+
+        command
+    }
+
+    // The command run on the remote for a single command, when every command runs in its own shell
+    fn command_line(&self, command: &str) -> String {
+        let command_to_run = self.wrap_command(match self.env.trim().is_empty() {
+            true => command.to_string(),
+            false => format!("{}; {}", self.env, command),
+        });
+
+        // Have the remote terminate the command once the deadline passes
+        match self.timeout {
+            Some(deadline) => shell::with_timeout(
+                &command_to_run,
+                deadline.as_secs(),
+                CommandRunner::KILL_GRACE,
+            ),
+            None => command_to_run,
+        }
+    }
+
+    // The command run on the remote for every command, when they run as a single script
+    fn script_line(&self, commands: &[String]) -> String {
+        let script = shell::script(
+            &self.env,
+            commands,
+            self.timeout
+                .map(|deadline| (deadline.as_secs(), CommandRunner::KILL_GRACE)),
+        );
+
+        // The watchdog and cancellation terminate the process group of the script, which must not include the shell
+        // that started it
+        self.wrap_command(shell::with_process_group(&format!(
+            "{} -c {}",
+            self.shell,
+            shell::quote(&script)
+        )))
+    }
+
+    // The exact command lines that are run on the remote for `commands`, in order
+    pub fn command_lines(&self, commands: &[String]) -> Vec<String> {
+        match self.mode {
+            ScriptMode::Script => vec![self.script_line(commands)],
+            ScriptMode::Commands => commands
+                .iter()
+                .map(|command| self.command_line(command))
+                .collect(),
+        }
+    }
+
+    // Verifies the remote can change to working_dir and run commands as run_as before running the script
+    pub async fn preflight(&self, transport: &dyn Transport) -> Result<(), Error> {
+        if self.working_dir.is_none() && self.run_as.is_none() {
+            return Ok(());
+        }
+
+        let output = match transport
+            .output(self.wrap_command(String::from("true")))
+            .await
+        {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => output,
+            Err(error) => return Err(command_error(None, &error)),
+        };
+
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let message = match &self.run_as {
+            Some(user) if stderr.contains("password is required") => format!(
+                "sudo requires a password to run commands as {}. Allow the Teleport user to run commands as {} without a password (NOPASSWD) in sudoers.",
+                user, user
+            ),
+            Some(user) if stderr.starts_with("sudo:") => {
+                format!("Unable to run commands as {}: {}", user, stderr)
+            }
+            _ => match &self.working_dir {
+                Some(dir) => format!("Unable to change to working directory {}: {}", dir, stderr),
+                None => stderr,
+            },
+        };
+
+        Err(Error::Execution {
+            command: None,
+            message,
+        })
+    }
+
+    // Runs every command on the remote in order, recording each command that ran in `steps`
+    pub async fn run(
+        &self,
+        transport: &dyn Transport,
+        host: &str,
+        commands: &[String],
+        cancellation: &Cancellation,
+        output: &dyn Output,
+        steps: &mut Vec<StepResult>,
+    ) -> Result<(), Error> {
+        self.preflight(transport).await?;
+
+        match self.mode {
+            ScriptMode::Script => {
+                self.run_script(transport, host, commands, cancellation, output, steps)
+                    .await
+            }
+            ScriptMode::Commands => {
+                self.run_commands(transport, host, commands, cancellation, output, steps)
+                    .await
+            }
+        }
+    }
+
+    // Runs each command in its own shell, stopping at the first failure
+    async fn run_commands(
+        &self,
+        transport: &dyn Transport,
+        host: &str,
+        commands: &[String],
+        cancellation: &Cancellation,
+        output: &dyn Output,
+        steps: &mut Vec<StepResult>,
+    ) -> Result<(), Error> {
+        // Iterate over all of the commands and run them syncronously
+        for command in commands.iter() {
+            // Another host failed, so don't start any new commands
+            if cancellation.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+
+            let command_to_run = self.command_line(command);
+            output.event(host, Event::Command(command));
+
+            let mut step = StepResult::new(command);
+            let progress = Mutex::new(ScriptProgress::new(self.capture));
+            let run = run_command(transport, host, command_to_run, &[], &progress, output);
+            let status = match self.timeout {
+                // The local deadline is a backstop for when the remote is unable to terminate the process itself
+                Some(deadline) => {
+                    let backstop = deadline + Duration::from_secs(CommandRunner::KILL_GRACE * 2);
+                    match timeout(backstop, run).await {
+                        Ok(status) => status,
+                        Err(_) => {
+                            let error = Error::Timeout {
+                                command: Some(command.to_string()),
+                                seconds: deadline.as_secs(),
+                            };
+                            step.error = Some(error.to_string());
+                            progress.lock().unwrap().record(&mut step);
+                            steps.push(step);
+                            return Err(error);
+                        }
+                    }
+                }
+                None => run.await,
+            };
+
+            progress.lock().unwrap().record(&mut step);
+            let status = match status {
+                Ok(status) => status,
+                // Commands are never retried, even if the connection was lost while they ran
+                Err(error) => {
+                    let error = command_error(Some(command), &error);
+                    step.error = Some(error.to_string());
+                    steps.push(step);
+                    return Err(error);
+                }
+            };
+            step.exit_code = status.code();
+
+            // `timeout` exits with 124 when the command was terminated, or 137 if it had to be killed
+            if let Some(deadline) = self.timeout {
+                if matches!(status.code(), Some(124) | Some(137))
+                    && progress.lock().unwrap().started.elapsed() >= deadline
+                {
+                    let error = Error::Timeout {
+                        command: Some(command.to_string()),
+                        seconds: deadline.as_secs(),
+                    };
+                    step.error = Some(error.to_string());
+                    steps.push(step);
+                    return Err(error);
+                }
+            }
+
+            steps.push(step);
+
+            // If any commit exits with a non-0 exit status code, stop execution of this task.
+            if status.code() != Some(0) {
+                return Err(Error::CommandFailed {
+                    command: command.to_string(),
+                    exit_code: status.code(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Runs every command as a single script in one shell, reporting the command that failed from the script's markers.
+    // The script is terminated on the host as soon as another host fails.
+    async fn run_script(
+        &self,
+        transport: &dyn Transport,
+        host: &str,
+        commands: &[String],
+        cancellation: &Cancellation,
+        output: &dyn Output,
+        steps: &mut Vec<StepResult>,
+    ) -> Result<(), Error> {
+        // Another host failed before this one started
+        if cancellation.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let progress = Mutex::new(ScriptProgress::new(self.capture));
+        let mut cancelled: Option<Instant> = None;
+        let status = {
+            let run = run_command(
+                transport,
+                host,
+                self.script_line(commands),
+                commands,
+                &progress,
+                output,
+            );
+            tokio::pin!(run);
+
+            loop {
+                tokio::select! {
+                    status = &mut run => break Some(status),
+                    _ = tokio::time::sleep(Duration::from_millis(250)) => {
+                        // Another host failed, so terminate the script on the host and wait for it to exit
+                        if cancelled.is_none() && cancellation.is_cancelled() {
+                            cancelled = Some(Instant::now());
+                            let pid = progress.lock().unwrap().pid;
+                            match pid {
+                                Some(pid) => {
+                                    // The connection closing terminates the script as well, so errors don't matter
+                                    #[allow(unused_must_use)]
+                                    {
+                                        transport.output(self.wrap_command(shell::terminate(pid))).await;
+                                    }
+                                }
+                                None => break None,
+                            }
+                        }
+
+                        // A script that ignores SIGTERM is left behind
+                        if matches!(cancelled, Some(at) if at.elapsed() > Duration::from_secs(CommandRunner::KILL_GRACE)) {
+                            break None;
+                        }
+
+                        // The local deadline is a backstop for when the remote is unable to terminate the command itself
+                        if let Some(deadline) = self.timeout {
+                            let backstop = deadline + Duration::from_secs(CommandRunner::KILL_GRACE * 2);
+                            if progress.lock().unwrap().started.elapsed() > backstop {
+                                break None;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        let mut progress = progress.into_inner().unwrap();
+        let command = progress.command.map(|index| commands[index].as_str());
+
+        // Every command before the last one that started exited successfully
+        steps.append(&mut progress.finished);
+        let mut step = command.map(|command| {
+            let mut step = StepResult::new(command);
+            progress.record(&mut step);
+            step
+        });
+
+        let outcome = match status {
+            Some(Ok(status)) if cancelled.is_some() && !status.success() => Err(Error::Cancelled),
+            None if cancelled.is_some() => Err(Error::Cancelled),
+            Some(Ok(status)) if !progress.timed_out => {
+                if let Some(step) = step.as_mut() {
+                    step.exit_code = status.code();
+                }
+
+                match (status.code(), command) {
+                    (Some(0), _) => Ok(()),
+                    (code, Some(command)) => Err(Error::CommandFailed {
+                        command: command.to_string(),
+                        exit_code: code,
+                    }),
+                    // The script failed before its first command, e.g. because the shell doesn't exist
+                    (code, None) => Err(Error::Execution {
+                        command: None,
+                        message: format!(
+                            "the script exited with status {} before running any command",
+                            code.unwrap_or(-1)
+                        ),
+                    }),
+                }
+            }
+            Some(Err(error)) => Err(command_error(command, &error)),
+            // The watchdog, or the local backstop, terminated the script
+            _ => Err(Error::Timeout {
+                command: command.map(str::to_string),
+                seconds: self.timeout.unwrap_or_default().as_secs(),
+            }),
+        };
+
+        if let (Some(step), Err(error)) = (step.as_mut(), &outcome) {
+            if !matches!(error, Error::CommandFailed { .. }) {
+                step.error = Some(error.to_string());
+            }
+        }
+
+        steps.extend(step);
+        outcome
+    }
+}
+
+// The error for a command that couldn't be run, distinguishing a lost connection from other failures
+fn command_error(command: Option<&str>, error: &std::io::Error) -> Error {
+    let command = command.map(str::to_string);
+    let message = error.to_string();
+    match error.kind() {
+        std::io::ErrorKind::ConnectionAborted => Error::Disconnected { command, message },
+        _ => Error::Execution { command, message },
+    }
+}
+
+// Handles a marker printed by a script, returning the output that followed it on the same line
+fn handle_marker<'l>(
+    host: &str,
+    line: &'l str,
+    commands: &[String],
+    progress: &Mutex<ScriptProgress>,
+    output: &dyn Output,
+) -> &'l str {
+    let (value, rest) = line.split_once(shell::MARKER_END).unwrap_or((line, ""));
+    let mut progress = progress.lock().unwrap();
+
+    if value == shell::MARKER_TIMEOUT {
+        progress.timed_out = true;
+    } else if let Some(pid) = value.strip_prefix(shell::MARKER_PID) {
+        progress.pid = pid.parse().ok();
+    } else if let Some(command) = value
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| commands.get(i).map(|c| (i, c)))
+    {
+        // Starting a command means the previous one exited successfully
+        if let Some(previous) = progress.command {
+            let mut step = StepResult::new(&commands[previous]);
+            step.exit_code = Some(0);
+            progress.record(&mut step);
+            progress.finished.push(step);
+        }
+        progress.command = Some(command.0);
+        progress.started = Instant::now();
+        output.event(host, Event::Command(command.1));
+    }
+
+    rest
+}
+
+// Reports each line read from a remote output stream as soon as it arrives
+async fn stream_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    host: &str,
+    is_stderr: bool,
+    commands: &[String],
+    progress: &Mutex<ScriptProgress>,
+    output: &dyn Output,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer).await? == 0 {
+            return Ok(());
+        }
+
+        let line = String::from_utf8_lossy(&buffer);
+        let mut line = line.trim_end_matches(&['\r', '\n'][..]);
+
+        // A marker may follow output that didn't end in a newline
+        while let Some((text, marker)) = line.split_once(shell::MARKER_START) {
+            if !text.is_empty() {
+                output.event(
+                    host,
+                    Event::Line {
+                        line: text,
+                        is_stderr,
+                    },
+                );
+                progress.lock().unwrap().capture(text, is_stderr);
+            }
+            line = handle_marker(host, marker, commands, progress, output);
+            if line.is_empty() {
+                break;
+            }
+        }
+
+        if !line.is_empty() {
+            output.event(host, Event::Line { line, is_stderr });
+            progress.lock().unwrap().capture(line, is_stderr);
+        }
+    }
+}
+
+// Runs a single command on the remote, streaming stdout and stderr while it executes.
+// Markers printed by scripts are matched against `commands` and recorded in `progress`.
+async fn run_command(
+    transport: &dyn Transport,
+    host: &str,
+    command: String,
+    commands: &[String],
+    progress: &Mutex<ScriptProgress>,
+    output: &dyn Output,
+) -> std::io::Result<ExitStatus> {
+    let mut process = transport.spawn(command).await?;
+
+    // Both streams have to be drained concurrently, otherwise a full pipe would stall the remote process
+    let stdout = process.stdout().unwrap();
+    let stderr = process.stderr().unwrap();
+    tokio::try_join!(
+        stream_lines(stdout, host, false, commands, progress, output),
+        stream_lines(stderr, host, true, commands, progress, output)
+    )?;
+
+    process.wait().await
+}
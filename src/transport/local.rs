@@ -0,0 +1,271 @@
+use futures::future::BoxFuture;
+use std::{
+    collections::HashSet,
+    io,
+    os::unix::{
+        fs::{FileExt, PermissionsExt},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
+use tokio::{
+    fs::File,
+    process::{Child, Command},
+};
+
+use crate::{
+    error::Error,
+    output::Output,
+    transport::{Connector, FileSystem, Process, Reader, RemoteFile, RemoteSource, Transport},
+};
+
+/// A host faked on the local machine: commands run in a local `sh` from `root`, and relative paths are
+/// resolved against `root`, as they would be against the home directory of a remote user. Commands still see the
+/// whole local filesystem, so files may only be accessed through relative paths, which mean the same to both.
+#[derive(Debug, Clone)]
+pub struct LocalTransport {
+    root: PathBuf,
+    failing_uploads: bool,
+}
+
+impl LocalTransport {
+    pub fn new(root: &Path) -> LocalTransport {
+        LocalTransport {
+            root: root.to_path_buf(),
+            failing_uploads: false,
+        }
+    }
+
+    /// Makes every write to a file created on the host fail, as if the connection dropped during an upload
+    pub fn failing_uploads(&mut self) -> &mut Self {
+        self.failing_uploads = true;
+        self
+    }
+
+    // Resolves a path on the fake host. An absolute path would be a different file to the commands than to the
+    // file system, and could reach outside of `root`, so it is refused.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        if Path::new(path).is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the fake host only accepts paths relative to its root, not {}",
+                    path
+                ),
+            ));
+        }
+
+        Ok(self.root.join(path))
+    }
+}
+
+impl<'a> Process<'a> for Child {
+    fn stdout(&mut self) -> Option<Reader> {
+        self.stdout.take().map(|stdout| Box::new(stdout) as Reader)
+    }
+
+    fn stderr(&mut self) -> Option<Reader> {
+        self.stderr.take().map(|stderr| Box::new(stderr) as Reader)
+    }
+
+    fn wait(mut self: Box<Self>) -> BoxFuture<'a, io::Result<ExitStatus>> {
+        Box::pin(async move { Child::wait(&mut self).await })
+    }
+}
+
+/// A file written to a host faked on the local machine
+struct LocalFile {
+    file: std::fs::File,
+    failing: bool,
+}
+
+impl<'a> RemoteFile<'a> for LocalFile {
+    // Local disk is fast enough for a fake host to write to it in place
+    fn write_at<'b>(&'b self, offset: u64, buf: &'b [u8]) -> BoxFuture<'b, io::Result<()>> {
+        Box::pin(async move {
+            if self.failing {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the connection to the fake host dropped",
+                ));
+            }
+            FileExt::write_all_at(&self.file, buf, offset)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A file read from a host faked on the local machine
+struct LocalSource {
+    file: std::fs::File,
+    size: u64,
+}
+
+impl<'a> RemoteSource<'a> for LocalSource {
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut buffer = vec![0u8; len];
+            let mut read = 0;
+            while read < len {
+                match FileExt::read_at(&self.file, &mut buffer[read..], offset + read as u64)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            buffer.truncate(read);
+
+            Ok(buffer)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl<'a> FileSystem<'a> for LocalTransport {
+    fn create_dir<'b>(&'b self, path: &'b str) -> BoxFuture<'b, io::Result<()>> {
+        Box::pin(async move {
+            let path = self.resolve(path)?;
+            tokio::fs::create_dir(&path).await?;
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o775)).await
+        })
+    }
+
+    fn create<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteFile<'b> + 'b>>> {
+        Box::pin(async move {
+            let file = File::create(self.resolve(path)?).await?.into_std().await;
+            Ok(Box::new(LocalFile {
+                file,
+                failing: self.failing_uploads,
+            }) as Box<dyn RemoteFile<'b> + 'b>)
+        })
+    }
+
+    fn open<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteSource<'b> + 'b>>> {
+        Box::pin(async move {
+            let file = File::open(self.resolve(path)?).await?.into_std().await;
+            let size = file.metadata()?.len();
+            Ok(Box::new(LocalSource { file, size }) as Box<dyn RemoteSource<'b> + 'b>)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn spawn<'a>(
+        &'a self,
+        command: String,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Process<'a> + 'a>>> {
+        Box::pin(async move {
+            // Like a command started by sshd, the command can't signal the process group of whoever started it
+            let mut std_command = std::process::Command::new("sh");
+            std_command.process_group(0);
+
+            let child = Command::from(std_command)
+                .arg("-c")
+                .arg(command)
+                .current_dir(&self.root)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            Ok(Box::new(child) as Box<dyn Process<'a> + 'a>)
+        })
+    }
+
+    fn files<'a>(&'a self) -> BoxFuture<'a, io::Result<Box<dyn FileSystem<'a> + 'a>>> {
+        Box::pin(async move { Ok(Box::new(self.clone()) as Box<dyn FileSystem<'a> + 'a>) })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Connects to hosts faked on the local machine, each with its own directory in `root`
+#[derive(Debug, Clone)]
+pub struct LocalConnector {
+    root: PathBuf,
+    unreachable: HashSet<String>,
+    failing_uploads: HashSet<String>,
+}
+
+impl LocalConnector {
+    pub fn new(root: &Path) -> LocalConnector {
+        LocalConnector {
+            root: root.to_path_buf(),
+            unreachable: HashSet::new(),
+            failing_uploads: HashSet::new(),
+        }
+    }
+
+    /// Makes connecting to `host` fail, as if it were down
+    pub fn unreachable(&mut self, host: &str) -> &mut Self {
+        self.unreachable.insert(host.to_string());
+        self
+    }
+
+    /// Makes every upload to `host` fail, as if the connection dropped during the upload
+    pub fn failing_uploads(&mut self, host: &str) -> &mut Self {
+        self.failing_uploads.insert(host.to_string());
+        self
+    }
+
+    /// The directory `host` runs commands from, and resolves relative paths against
+    pub fn host_root(&self, host: &str) -> PathBuf {
+        self.root.join(host)
+    }
+}
+
+impl Connector for LocalConnector {
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        _output: &'a dyn Output,
+    ) -> BoxFuture<'a, Result<Box<dyn Transport>, Error>> {
+        Box::pin(async move {
+            if self.unreachable.contains(host) {
+                return Err(Error::Connection(format!(
+                    "failed to connect to the remote host {}: Connection refused",
+                    host
+                )));
+            }
+
+            let root = self.host_root(host);
+            // The fake host can't be reached without its directory
+            tokio::fs::create_dir_all(&root).await.map_err(|error| {
+                Error::Connection(format!(
+                    "failed to connect to the remote host {}: unable to create {}: {}",
+                    host,
+                    root.display(),
+                    error
+                ))
+            })?;
+            let mut transport = LocalTransport::new(&root);
+            if self.failing_uploads.contains(host) {
+                transport.failing_uploads();
+            }
+            Ok(Box::new(transport) as Box<dyn Transport>)
+        })
+    }
+}
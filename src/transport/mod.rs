@@ -0,0 +1,118 @@
+use futures::future::BoxFuture;
+use std::{io, process::ExitStatus};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{error::Error, output::Output};
+
+pub mod local;
+pub mod session;
+
+pub use local::{LocalConnector, LocalTransport};
+
+/// A stream of output from a command running on a host
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A command running on a host
+pub trait Process<'a>: Send {
+    /// Takes the stdout of the command, so it can be read while the command runs
+    fn stdout(&mut self) -> Option<Reader>;
+
+    /// Takes the stderr of the command, so it can be read while the command runs
+    fn stderr(&mut self) -> Option<Reader>;
+
+    /// Waits for the command to exit
+    fn wait(self: Box<Self>) -> BoxFuture<'a, io::Result<ExitStatus>>;
+}
+
+/// A file being written on a host
+pub trait RemoteFile<'a>: Send + Sync {
+    /// Writes all of `buf` at `offset`. Any number of writes may be in flight at the same time.
+    fn write_at<'b>(&'b self, offset: u64, buf: &'b [u8]) -> BoxFuture<'b, io::Result<()>>;
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// A file being read from a host
+pub trait RemoteSource<'a>: Send + Sync {
+    /// The size of the file when it was opened, if the host reported it
+    fn size(&self) -> Option<u64>;
+
+    /// Reads `len` bytes at `offset`, or fewer once the end of the file is reached.
+    /// Any number of reads may be in flight at the same time.
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Access to the file system of a host, e.g. over SFTP
+pub trait FileSystem<'a>: Send + Sync {
+    /// Creates a single directory that is writable by its owner and group
+    fn create_dir<'b>(&'b self, path: &'b str) -> BoxFuture<'b, io::Result<()>>;
+
+    /// Creates, or truncates, a file to write to
+    fn create<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteFile<'b> + 'b>>>;
+
+    /// Opens a file to read
+    fn open<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteSource<'b> + 'b>>>;
+
+    fn close(self: Box<Self>) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// A connection to a host that commands are run on, and files are written to.
+/// A command that failed because the connection was lost returns an `io::ErrorKind::ConnectionAborted` error.
+pub trait Transport: Send + Sync {
+    /// Starts running `command` in the shell of the host, with stdout and stderr piped
+    fn spawn<'a>(&'a self, command: String)
+        -> BoxFuture<'a, io::Result<Box<dyn Process<'a> + 'a>>>;
+
+    /// Opens the file system of the host
+    fn files<'a>(&'a self) -> BoxFuture<'a, io::Result<Box<dyn FileSystem<'a> + 'a>>>;
+
+    /// Closes the connection
+    fn close(self: Box<Self>) -> BoxFuture<'static, io::Result<()>>;
+
+    /// Runs `command` in the shell of the host to completion, collecting its output
+    fn output<'a>(&'a self, command: String) -> BoxFuture<'a, io::Result<std::process::Output>> {
+        Box::pin(async move {
+            let mut process = self.spawn(command).await?;
+            let mut stdout = process.stdout();
+            let mut stderr = process.stderr();
+
+            // Both streams have to be drained concurrently, otherwise a full pipe would stall the process
+            let (stdout, stderr) = tokio::try_join!(read_all(&mut stdout), read_all(&mut stderr))?;
+            let status = process.wait().await?;
+
+            Ok(std::process::Output {
+                status,
+                stdout,
+                stderr,
+            })
+        })
+    }
+}
+
+/// Opens connections to hosts
+pub trait Connector: Send + Sync {
+    /// Connects to `host`, reporting retries to `output`
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        output: &'a dyn Output,
+    ) -> BoxFuture<'a, Result<Box<dyn Transport>, Error>>;
+}
+
+// Reads a stream until it ends
+async fn read_all(reader: &mut Option<Reader>) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if let Some(reader) = reader {
+        reader.read_to_end(&mut buffer).await?;
+    }
+
+    Ok(buffer)
+}
@@ -0,0 +1,241 @@
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use openssh::{RemoteChild, Session, Stdio};
+use openssh_sftp_client::{file::File, metadata::Permissions, Sftp};
+use std::{
+    io::{self, SeekFrom},
+    process::ExitStatus,
+    sync::Mutex,
+};
+use tokio::io::AsyncSeekExt;
+
+use crate::{
+    connection::{describe_error, ConnectionBuilder},
+    error::Error,
+    output::Output,
+    transport::{Connector, FileSystem, Process, Reader, RemoteFile, RemoteSource, Transport},
+};
+
+// Converts an openssh error to an io::Error, keeping whether the connection was lost
+fn io_error(error: openssh::Error) -> io::Error {
+    let kind = match error {
+        openssh::Error::Disconnected => io::ErrorKind::ConnectionAborted,
+        _ => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, describe_error(&error))
+}
+
+impl<'s> Process<'s> for RemoteChild<'s> {
+    fn stdout(&mut self) -> Option<Reader> {
+        RemoteChild::stdout(self)
+            .take()
+            .map(|stdout| Box::new(stdout) as Reader)
+    }
+
+    fn stderr(&mut self) -> Option<Reader> {
+        RemoteChild::stderr(self)
+            .take()
+            .map(|stderr| Box::new(stderr) as Reader)
+    }
+
+    fn wait(self: Box<Self>) -> BoxFuture<'s, io::Result<ExitStatus>> {
+        Box::pin(async move { RemoteChild::wait(*self).await.map_err(io_error) })
+    }
+}
+
+impl<'s> RemoteFile<'s> for File<'s> {
+    fn write_at<'b>(&'b self, offset: u64, buf: &'b [u8]) -> BoxFuture<'b, io::Result<()>> {
+        // Every clone of the file has an offset of its own, so each write gets a clone to send its request from
+        let mut file = self.clone();
+        Box::pin(async move {
+            file.seek(SeekFrom::Start(offset)).await?;
+            File::write_all(&mut file, buf)
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'s, io::Result<()>> {
+        Box::pin(async move { File::close(*self).await.map_err(io::Error::other) })
+    }
+}
+
+/// A file opened for reading over SFTP, with the size it had when it was opened
+struct SftpSource<'s> {
+    file: File<'s>,
+    size: Option<u64>,
+}
+
+impl<'s> RemoteSource<'s> for SftpSource<'s> {
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        // Every clone of the file has an offset of its own, so each read gets a clone to send its request from
+        let mut file = self.file.clone();
+        Box::pin(async move {
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            // The server may return less than was asked for, so read until `len` bytes or the end of the file
+            let mut buffer = Vec::with_capacity(len);
+            while buffer.len() < len {
+                let remaining = (len - buffer.len()) as u32;
+                match file
+                    .read(remaining, BytesMut::with_capacity(remaining as usize))
+                    .await
+                    .map_err(io::Error::other)?
+                {
+                    Some(chunk) if !chunk.is_empty() => buffer.extend_from_slice(&chunk),
+                    _ => break,
+                }
+            }
+
+            Ok(buffer)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'s, io::Result<()>> {
+        Box::pin(async move { self.file.close().await.map_err(io::Error::other) })
+    }
+}
+
+/// The file system of a host, over the SFTP subsystem of an ssh session
+struct SftpFileSystem<'s> {
+    sftp: Sftp,
+    /// The sftp subsystem, which has to outlive `sftp`
+    _child: Mutex<RemoteChild<'s>>,
+}
+
+impl<'s> FileSystem<'s> for SftpFileSystem<'s> {
+    fn create_dir<'b>(&'b self, path: &'b str) -> BoxFuture<'b, io::Result<()>> {
+        Box::pin(async move {
+            let mut perm = Permissions::new();
+            perm.set_execute_by_owner(true);
+            perm.set_execute_by_group(true);
+            perm.set_execute_by_other(true);
+            perm.set_read_by_owner(true);
+            perm.set_read_by_group(true);
+            perm.set_read_by_other(true);
+            perm.set_write_by_owner(true);
+            perm.set_write_by_group(true);
+
+            let mut fs = self.sftp.fs();
+            fs.create_dir(path).await.map_err(io::Error::other)?;
+            fs.set_permissions(path, perm)
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+
+    fn create<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteFile<'b> + 'b>>> {
+        Box::pin(async move {
+            let file = self
+                .sftp
+                .options()
+                .read(true)
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)
+                .await
+                .map_err(io::Error::other)?;
+
+            Ok(Box::new(file) as Box<dyn RemoteFile<'b> + 'b>)
+        })
+    }
+
+    fn open<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxFuture<'b, io::Result<Box<dyn RemoteSource<'b> + 'b>>> {
+        Box::pin(async move {
+            let mut file = self
+                .sftp
+                .options()
+                .read(true)
+                .open(path)
+                .await
+                .map_err(io::Error::other)?;
+            let size = file.metadata().await.map_err(io::Error::other)?.len();
+
+            Ok(Box::new(SftpSource { file, size }) as Box<dyn RemoteSource<'b> + 'b>)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'s, io::Result<()>> {
+        Box::pin(async move { self.sftp.close().await.map_err(io::Error::other) })
+    }
+}
+
+impl Transport for Session {
+    fn spawn<'a>(
+        &'a self,
+        command: String,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Process<'a> + 'a>>> {
+        Box::pin(async move {
+            let child = self
+                .shell(command)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .await
+                .map_err(io_error)?;
+
+            Ok(Box::new(child) as Box<dyn Process<'a> + 'a>)
+        })
+    }
+
+    fn files<'a>(&'a self) -> BoxFuture<'a, io::Result<Box<dyn FileSystem<'a> + 'a>>> {
+        Box::pin(async move {
+            let mut child = self
+                .subsystem("sftp")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .await
+                .map_err(|error| {
+                    io::Error::other(format!(
+                        "Failed to setup SFTP subsystem on remote: {}",
+                        describe_error(&error)
+                    ))
+                })?;
+
+            let sftp = Sftp::new(
+                child.stdin().take().unwrap(),
+                child.stdout().take().unwrap(),
+                Default::default(),
+            )
+            .await
+            .map_err(|error| {
+                io::Error::other(format!("Failed to create SFTP instance: {}", error))
+            })?;
+
+            Ok(Box::new(SftpFileSystem {
+                sftp,
+                _child: Mutex::new(child),
+            }) as Box<dyn FileSystem<'a> + 'a>)
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(async move { Session::close(*self).await.map_err(io_error) })
+    }
+}
+
+impl Connector for ConnectionBuilder {
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        output: &'a dyn Output,
+    ) -> BoxFuture<'a, Result<Box<dyn Transport>, Error>> {
+        Box::pin(async move {
+            let session = ConnectionBuilder::connect(self, host, output).await?;
+            Ok(Box::new(session) as Box<dyn Transport>)
+        })
+    }
+}
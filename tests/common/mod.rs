@@ -0,0 +1,58 @@
+#![allow(dead_code)]
+
+use clap::Parser;
+use drone_teleport::{
+    config::state::Config,
+    output::{Event, Output},
+};
+use serde_json::Value;
+use std::{path::Path, sync::Mutex};
+
+// Parses the plugin settings from command line arguments, as the plugin would from PLUGIN_* variables
+pub fn config(args: &[&str]) -> Config {
+    let mut argv = vec![
+        "drone-teleport",
+        "--username",
+        "bot",
+        "--proxy",
+        "teleport.example.com:443",
+        "--data-path",
+        "/opt/teleport/home",
+    ];
+    argv.extend_from_slice(args);
+    Config::try_parse_from(argv).unwrap()
+}
+
+// The value of settings:script, listing `commands`
+pub fn script(commands: &[&str]) -> String {
+    serde_json::json!({ "commands": commands }).to_string()
+}
+
+// Reads the JSON report written to `path`
+pub fn report(path: &Path) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// Keeps every event as a line of text, so tests can check what was reported
+#[derive(Debug, Default)]
+pub struct Recorder(pub Mutex<Vec<String>>);
+
+impl Recorder {
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Output for Recorder {
+    fn event(&self, host: &str, event: Event) {
+        let line = match event {
+            Event::Command(command) => format!("{}: $ {}", host, command),
+            Event::Line { line, is_stderr } => match is_stderr {
+                true => format!("{}: ! {}", host, line),
+                false => format!("{}: {}", host, line),
+            },
+            event => format!("{}: {:?}", host, event),
+        };
+        self.0.lock().unwrap().push(line);
+    }
+}
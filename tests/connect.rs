@@ -0,0 +1,543 @@
+mod common;
+
+use common::{config, report, script, Recorder};
+use drone_teleport::{
+    config::{rollout::Cancellation, state::SubCommand},
+    transport::{LocalConnector, LocalTransport},
+    CommandRunner, Error, ScriptMode,
+};
+use std::{collections::BTreeMap, sync::Arc};
+
+// Runs the connect operation against hosts faked by `connector`, returning the exit code
+async fn connect(connector: LocalConnector, args: &[&str]) -> i32 {
+    connect_in(connector, &[], args).await
+}
+
+// Runs the connect operation like `connect`, with `env` added to the environment of the runner
+async fn connect_in(connector: LocalConnector, env: &[(&str, &str)], args: &[&str]) -> i32 {
+    let mut cfg = config(args);
+    for (name, value) in env {
+        cfg.runner_env.insert(name.to_string(), value.to_string());
+    }
+    match &cfg.cmd {
+        SubCommand::Connect(connect) => connect
+            .connect_with(&cfg, Arc::new(connector))
+            .await
+            .unwrap_or_else(|error| error.exit_code()),
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn runs_the_script_on_every_host() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["mkdir -p app", "cd app", "echo $RELEASE > release"]);
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1,web-2",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+            "--env",
+            r#"{"RELEASE": "v1.2.3"}"#,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 0);
+    for host in ["web-1", "web-2"] {
+        let release = root.path().join(host).join("app/release");
+        assert_eq!(std::fs::read_to_string(release).unwrap(), "v1.2.3\n");
+    }
+
+    let report = report(&report_path);
+    assert_eq!(report["status"], "succeeded");
+    assert_eq!(report["operation"], "connect");
+    assert_eq!(report["hosts"][0]["completed"], 3);
+    assert_eq!(report["hosts"][0]["steps"].as_array().unwrap().len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_the_command_that_failed() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let junit_path = root.path().join("junit.xml");
+    let commands = script(&["echo one", "exit 3", "echo three"]);
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+            "--junit-path",
+            junit_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    let report = report(&report_path);
+    let host = &report["hosts"][0];
+    assert_eq!(host["status"], "failed");
+    assert_eq!(host["completed"], 1);
+    assert_eq!(host["failure"]["name"], "exit 3");
+    assert_eq!(host["failure"]["exit_code"], 3);
+    assert_eq!(host["failure"]["message"], "exit 3 (exit 3)");
+
+    let junit = std::fs::read_to_string(junit_path).unwrap();
+    assert!(junit.contains(r#"tests="3" failures="1" errors="0" skipped="1""#));
+    assert!(junit.contains("<system-out>one\n</system-out>"));
+    assert!(junit.contains(r#"<skipped message="not run: an earlier command failed"/>"#));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_with_the_code_of_an_unreachable_host() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["true"]);
+    let mut connector = LocalConnector::new(root.path());
+    connector.unreachable("web-2");
+
+    let code = connect(
+        connector,
+        &[
+            "--hosts",
+            "web-1,web-2",
+            "--failure-policy",
+            "run-all",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 3);
+    let report = report(&report_path);
+    assert_eq!(report["hosts"][0]["status"], "succeeded");
+    assert_eq!(report["hosts"][1]["status"], "failed");
+    assert_eq!(report["hosts"][1]["connected"], false);
+    assert_eq!(
+        report["hosts"][1]["failure"]["error"],
+        "failed to connect to the remote host web-2: Connection refused"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_does_not_connect() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["touch ran"]);
+    let mut connector = LocalConnector::new(root.path());
+    connector.unreachable("web-1");
+
+    let code = connect(
+        connector,
+        &[
+            "--hosts",
+            "web-1,web-2",
+            "--dry-run",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 0);
+    assert!(!root.path().join("web-2/ran").exists());
+    assert!(!report_path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn times_out_long_running_commands() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["sleep 30"]);
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--timeout",
+            "1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script-mode",
+            "commands",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 4);
+    let report = report(&report_path);
+    assert_eq!(report["hosts"][0]["failure"]["name"], "sleep 30");
+    assert_eq!(
+        report["hosts"][0]["failure"]["error"],
+        "timed out after 1 seconds"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn times_out_long_running_commands_in_a_script() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["echo started", "sleep 30", "touch ran"]);
+
+    // The watchdog only terminates the script, so the test itself keeps running
+    let started = std::time::Instant::now();
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--timeout",
+            "1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 4);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    let report = report(&report_path);
+    assert_eq!(report["hosts"][0]["failure"]["name"], "sleep 30");
+    assert_eq!(
+        report["hosts"][0]["failure"]["error"],
+        "timed out after 1 seconds"
+    );
+    assert!(!root.path().join("web-1/ran").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checks_the_working_dir_before_running_commands() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&["touch ran"]);
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--working-dir",
+            "missing",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 2);
+    assert!(!root.path().join("web-1/ran").exists());
+    let error = report(&report_path)["hosts"][0]["failure"]["error"].to_string();
+    assert!(error.contains("Unable to change to working directory missing"));
+}
+
+#[tokio::test]
+async fn streams_output_as_commands_run() {
+    let root = tempfile::tempdir().unwrap();
+    let transport = LocalTransport::new(root.path());
+    let recorder = Recorder::default();
+    let commands = vec![
+        String::from("echo \"$GREETING\""),
+        String::from("echo oops >&2"),
+    ];
+
+    let mut steps = Vec::new();
+    let mut runner = CommandRunner::new();
+    // A single script writes stdout and stderr to separate pipes, so the order they are read in isn't deterministic.
+    // Each command in its own shell is read to the end before the next one starts.
+    runner
+        .mode(ScriptMode::Commands)
+        .env(&BTreeMap::from([(
+            String::from("GREETING"),
+            String::from("hello world"),
+        )]))
+        .capture(true);
+    runner
+        .run(
+            &transport,
+            "web-1",
+            &commands,
+            &Cancellation::default(),
+            &recorder,
+            &mut steps,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recorder.lines(),
+        [
+            "web-1: $ echo \"$GREETING\"",
+            "web-1: hello world",
+            "web-1: $ echo oops >&2",
+            "web-1: ! oops",
+        ]
+    );
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0].stdout, "hello world\n");
+    assert_eq!(steps[1].stderr, "oops\n");
+}
+
+#[tokio::test]
+async fn stops_at_the_first_failed_command() {
+    let root = tempfile::tempdir().unwrap();
+    let transport = LocalTransport::new(root.path());
+    let commands = vec![String::from("false"), String::from("touch ran")];
+
+    for mode in [ScriptMode::Script, ScriptMode::Commands] {
+        let mut steps = Vec::new();
+        let mut runner = CommandRunner::new();
+        runner.mode(mode);
+        let error = runner
+            .run(
+                &transport,
+                "web-1",
+                &commands,
+                &Cancellation::default(),
+                &drone_teleport::Silent,
+                &mut steps,
+            )
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            Error::CommandFailed {
+                command: String::from("false"),
+                exit_code: Some(1),
+            }
+        );
+        assert_eq!(error.exit_code(), 1);
+        assert_eq!(steps.len(), 1);
+        assert!(!root.path().join("ran").exists());
+    }
+}
+
+#[tokio::test]
+async fn stops_when_another_host_failed() {
+    let root = tempfile::tempdir().unwrap();
+    let transport = LocalTransport::new(root.path());
+    let cancellation = Cancellation::default();
+    cancellation.cancel();
+
+    let mut steps = Vec::new();
+    let mut runner = CommandRunner::new();
+    runner.mode(ScriptMode::Commands);
+    let error = runner
+        .run(
+            &transport,
+            "web-1",
+            &[String::from("true")],
+            &cancellation,
+            &drone_teleport::Silent,
+            &mut steps,
+        )
+        .await
+        .unwrap_err();
+
+    assert_eq!(error, Error::Cancelled);
+    assert!(steps.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn terminates_the_script_of_other_hosts_when_a_host_fails() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let commands = script(&[
+        r#"case "$PWD" in */web-1) exit 3;; esac"#,
+        "sleep 30",
+        "touch ran",
+    ]);
+
+    let started = std::time::Instant::now();
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1,web-2",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &commands,
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    let report = report(&report_path);
+    let hosts = report["hosts"].as_array().unwrap();
+    let host = |name: &str| hosts.iter().find(|host| host["host"] == name).unwrap();
+    assert_eq!(host("web-1")["status"], "failed");
+    assert_eq!(host("web-2")["status"], "cancelled");
+    assert!(!root.path().join("web-2/ran").exists());
+}
+
+#[test]
+fn exports_custom_settings_but_not_plugin_settings() {
+    let mut cfg = config(&[
+        "--hosts",
+        "web-1",
+        "connect",
+        "--script",
+        &script(&["true"]),
+    ]);
+    cfg.runner_env
+        .insert("PLUGIN_OP".to_string(), "connect".to_string());
+    cfg.runner_env
+        .insert("PLUGIN_GREETING".to_string(), "hello".to_string());
+    let vars = match &cfg.cmd {
+        SubCommand::Connect(connect) => connect.get_env_vars(&cfg).unwrap(),
+        _ => unreachable!(),
+    };
+
+    assert_eq!(vars["GREETING"], "hello");
+    assert!(!vars.contains_key("OP"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn renders_template_variables_and_leaves_other_templates() {
+    let root = tempfile::tempdir().unwrap();
+    let greeting = "hello world; it's \"$HOME\" $(touch injected)";
+    let commands = script(&[
+        "echo {{ host }}-{{ host_index }} > host",
+        "echo '{{.Names}}' > format",
+        "echo {{ env.DRONE_TELEPORT_TEST_GREETING }} > greeting",
+        "echo \"Greeting: {{ env.DRONE_TELEPORT_TEST_GREETING }}\" > double",
+        "echo 'Greeting: {{ env.DRONE_TELEPORT_TEST_GREETING }}' > single",
+    ]);
+
+    let code = connect_in(
+        LocalConnector::new(root.path()),
+        &[("DRONE_TELEPORT_TEST_GREETING", greeting)],
+        &["--hosts", "web-1,web-2", "connect", "--script", &commands],
+    )
+    .await;
+
+    assert_eq!(code, 0);
+    for (index, host) in ["web-1", "web-2"].iter().enumerate() {
+        let read = |name: &str| std::fs::read_to_string(root.path().join(host).join(name)).unwrap();
+        assert_eq!(read("host"), format!("{}-{}\n", host, index));
+        assert_eq!(read("format"), "{{.Names}}\n");
+
+        // Values are quoted for the quotes they appear in, so they are inserted as they are and can't inject commands
+        assert_eq!(read("greeting"), format!("{}\n", greeting));
+        assert_eq!(read("double"), format!("Greeting: {}\n", greeting));
+        assert_eq!(read("single"), format!("Greeting: {}\n", greeting));
+        assert!(!root.path().join(host).join("injected").exists());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_on_a_script_that_is_not_json() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+
+    // settings:script listed as plain commands, instead of settings:script:commands
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            "echo hello",
+        ],
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    assert!(!root.path().join("web-1").exists());
+    assert!(report(&report_path)["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid settings:script"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_on_an_unknown_variable() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &script(&["echo {{ hots }}"]),
+        ],
+    )
+    .await;
+
+    // A typo isn't deployed as it is
+    assert_eq!(code, 1);
+    assert!(!root.path().join("web-1").exists());
+    assert!(report(&report_path)["error"]
+        .as_str()
+        .unwrap()
+        .contains("Unknown template variable `hots`"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_on_a_variable_without_a_value() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+
+    let code = connect(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "connect",
+            "--script",
+            &script(&["echo {{ labels.region }}"]),
+        ],
+    )
+    .await;
+
+    // The run fails before connecting to any host, and the test process is still alive to see it
+    assert_eq!(code, 1);
+    assert!(!root.path().join("web-1").exists());
+
+    let report = report(&report_path);
+    assert_eq!(report["status"], "failed");
+    assert!(report["error"].as_str().unwrap().contains("labels.region"));
+}
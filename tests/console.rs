@@ -0,0 +1,88 @@
+use drone_teleport::{config::console::Console, Error, Event};
+use std::time::Duration;
+
+// Formats without colors, so the text can be compared
+fn console(debug: bool) -> Console {
+    colored::control::set_override(false);
+    Console { debug }
+}
+
+#[test]
+fn formats_command_output() {
+    let console = console(false);
+    assert_eq!(
+        console.format_event("web-1", &Event::Command("make deploy")),
+        Some(String::from("web-1: make deploy"))
+    );
+    assert_eq!(
+        console.format_event(
+            "web-1",
+            &Event::Line {
+                line: "done",
+                is_stderr: true
+            }
+        ),
+        Some(String::from("web-1: done"))
+    );
+}
+
+#[test]
+fn formats_retries() {
+    let error = Error::Connection(String::from("connection timed out after 30 seconds"));
+    assert_eq!(
+        console(false).format_event(
+            "web-1",
+            &Event::Retrying {
+                error: &error,
+                delay: Duration::from_millis(2500),
+                attempt: 1,
+                retries: 2
+            }
+        ),
+        Some(String::from(
+            "web-1: Unable to connect: connection timed out after 30 seconds, retrying in 2.5 seconds (1/2)"
+        ))
+    );
+}
+
+#[test]
+fn only_formats_transfer_details_when_debugging() {
+    let event = Event::Extracting {
+        archive: "abc.tar.zst",
+        dst: "/srv/app",
+    };
+    assert_eq!(console(false).format_event("web-1", &event), None);
+    assert_eq!(
+        console(true).format_event("web-1", &event),
+        Some(String::from("web-1: Extracting abc.tar.zst to /srv/app"))
+    );
+}
+
+#[test]
+fn formats_failures() {
+    let console = console(false);
+    let failed = Error::CommandFailed {
+        command: String::from("make deploy"),
+        exit_code: Some(2),
+    };
+    let timeout = Error::Timeout {
+        command: Some(String::from("sleep 300")),
+        seconds: 120,
+    };
+
+    assert_eq!(
+        console.format_failure("web-1", &failed),
+        Some(String::from("Exit: 2"))
+    );
+    assert_eq!(
+        console.format_failure("web-1", &timeout),
+        Some(String::from(
+            "Command timed out on web-1: sleep 300 (120 seconds)"
+        ))
+    );
+    assert_eq!(
+        console.format_failure("web-1", &Error::Connection(String::from("refused"))),
+        Some(String::from("Unable to connect to Teleport target: web-1"))
+    );
+    assert_eq!(console.format_failure("web-1", &Error::Cancelled), None);
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use common::{config, report};
+use drone_teleport::{config::state::SubCommand, transport::LocalConnector};
+use std::{path::Path, sync::Arc};
+
+// Runs the fetch operation against hosts faked by `connector`, returning the exit code
+async fn fetch(connector: LocalConnector, args: &[&str], compress: bool) -> i32 {
+    let mut cfg = config(args);
+    let mut fetch = match &cfg.cmd {
+        SubCommand::Fetch(fetch) => fetch.to_owned(),
+        _ => unreachable!(),
+    };
+
+    // compress is a flag that is only turned off through PLUGIN_COMPRESS
+    fetch.compress = compress;
+    cfg.cmd = SubCommand::Fetch(fetch.to_owned());
+    fetch
+        .fetch_with(&cfg, Arc::new(connector))
+        .await
+        .unwrap_or_else(|error| error.exit_code())
+}
+
+// The number of fetch archives left behind in the home directory of a host
+fn archives(home: &Path) -> usize {
+    std::fs::read_dir(home)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".drone-teleport-")
+        })
+        .count()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fetches_files_from_every_host() {
+    // Large enough to take many reads, which complete out of order
+    let mut seed: u32 = 7;
+    let dump: Vec<u8> = (0..3_000_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+
+    for compress in [true, false] {
+        let root = tempfile::tempdir().unwrap();
+        let workspace = tempfile::tempdir().unwrap();
+        let report_path = root.path().join("report.json");
+        let connector = LocalConnector::new(root.path());
+
+        for host in ["web-1", "web-2"] {
+            let logs = connector.host_root(host).join("logs");
+            std::fs::create_dir_all(&logs).unwrap();
+            std::fs::write(logs.join("app.log"), format!("{}\n", host)).unwrap();
+            std::fs::write(logs.join("dump.log"), &dump).unwrap();
+            std::fs::write(logs.join("notes.txt"), "").unwrap();
+        }
+
+        let dst = workspace.path().join("fetched");
+        let files = serde_json::json!([{ "src": "logs/*.log", "dst": dst }]).to_string();
+        let code = fetch(
+            connector,
+            &[
+                "--hosts",
+                "web-1,web-2",
+                "--report-path",
+                report_path.to_str().unwrap(),
+                "fetch",
+                "--files",
+                &files,
+            ],
+            compress,
+        )
+        .await;
+
+        assert_eq!(code, 0);
+        for host in ["web-1", "web-2"] {
+            let logs = dst.join(host).join("logs");
+            assert_eq!(
+                std::fs::read_to_string(logs.join("app.log")).unwrap(),
+                format!("{}\n", host)
+            );
+            assert_eq!(std::fs::read(logs.join("dump.log")).unwrap(), dump);
+            assert!(!logs.join("notes.txt").exists());
+            assert_eq!(archives(&root.path().join(host)), 0);
+        }
+
+        let report = report(&report_path);
+        let step = &report["hosts"][0]["steps"][0];
+        assert_eq!(report["operation"], "fetch");
+        assert_eq!(report["hosts"][0]["completed"], 1);
+        assert_eq!(step["name"], "logs/*.log");
+        assert_eq!(step["bytes"], step["size"]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_when_nothing_matches_on_the_host() {
+    let root = tempfile::tempdir().unwrap();
+    let workspace = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let files =
+        serde_json::json!([{ "src": "missing/*.log", "dst": workspace.path() }]).to_string();
+
+    let code = fetch(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "fetch",
+            "--files",
+            &files,
+        ],
+        true,
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    let failure = &report(&report_path)["hosts"][0]["failure"];
+    assert_eq!(failure["name"], "missing/*.log");
+    assert!(failure["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("unable to create remote archive (exit 2)"));
+    assert!(!workspace.path().join("web-1").exists());
+
+    // What tar wrote before it failed is deleted from the host as well
+    assert_eq!(archives(&root.path().join("web-1")), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_hosts_in_different_clusters_apart() {
+    let root = tempfile::tempdir().unwrap();
+    let workspace = tempfile::tempdir().unwrap();
+    let connector = LocalConnector::new(root.path());
+
+    // The same node name in two clusters
+    let hosts = ["web@leaf-1.example.com", "web@leaf-2.example.com"];
+    for host in hosts {
+        let logs = connector.host_root(host).join("logs");
+        std::fs::create_dir_all(&logs).unwrap();
+        std::fs::write(logs.join("app.log"), format!("{}\n", host)).unwrap();
+    }
+
+    let dst = workspace.path().join("fetched");
+    let files = serde_json::json!([{ "src": "logs/app.log", "dst": dst }]).to_string();
+    let code = fetch(
+        connector,
+        &["--hosts", &hosts.join(","), "fetch", "--files", &files],
+        true,
+    )
+    .await;
+
+    assert_eq!(code, 0);
+    for host in hosts {
+        assert_eq!(
+            std::fs::read_to_string(dst.join(host).join("logs/app.log")).unwrap(),
+            format!("{}\n", host)
+        );
+    }
+    assert!(!dst.join("web").exists());
+}
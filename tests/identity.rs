@@ -0,0 +1,106 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use drone_teleport::config::identity::Certificate;
+use std::{path::Path, time::Duration};
+
+// Loads one of the certificates in tests/fixtures/identity, signed by a throwaway CA with ssh-keygen
+fn fixture(name: &str) -> Certificate {
+    Certificate::load(&Path::new("tests/fixtures/identity").join(name)).unwrap()
+}
+
+#[test]
+fn parses_an_ed25519_certificate() {
+    let certificate = fixture("ed25519-cert.pub");
+
+    assert_eq!(certificate.key_type, "ssh-ed25519-cert-v01@openssh.com");
+    assert_eq!(certificate.key_id, "bot-ed25519");
+    assert_eq!(certificate.principals, ["ci", "deploy"]);
+    assert_eq!(certificate.valid_after, 0);
+    assert_eq!(certificate.valid_before, u64::MAX);
+    assert!(certificate.extensions.contains(&String::from("permit-pty")));
+    assert_eq!(certificate.describe_expiry(), "never expires");
+    assert!(certificate
+        .validate("deploy", Duration::from_secs(3600))
+        .is_ok());
+}
+
+#[test]
+fn parses_an_rsa_certificate() {
+    let certificate = fixture("rsa-cert.pub");
+
+    assert_eq!(certificate.key_type, "ssh-rsa-cert-v01@openssh.com");
+    assert_eq!(certificate.key_id, "bot-rsa");
+    assert_eq!(certificate.principals, ["ci"]);
+    assert!(certificate
+        .validate("ci", Duration::from_secs(3600))
+        .is_ok());
+}
+
+#[test]
+fn rejects_an_expired_certificate() {
+    let certificate = fixture("expired-cert.pub");
+
+    assert_eq!(certificate.valid_after, 1_577_836_800);
+    assert_eq!(certificate.valid_before, 1_577_923_200);
+    let error = certificate
+        .validate("ci", Duration::from_secs(0))
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("The Machine ID certificate expired"));
+    assert!(certificate.describe_expiry().starts_with("expired"));
+}
+
+#[test]
+fn rejects_a_certificate_that_is_not_valid_yet() {
+    let error = fixture("future-cert.pub")
+        .validate("ci", Duration::from_secs(0))
+        .unwrap_err();
+
+    assert!(error
+        .to_string()
+        .starts_with("The Machine ID certificate is not valid yet"));
+}
+
+#[test]
+fn rejects_a_user_that_is_not_a_principal() {
+    let certificate = fixture("rsa-cert.pub");
+
+    assert!(!certificate.permits("root"));
+    let error = certificate
+        .validate("root", Duration::from_secs(0))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The Machine ID certificate doesn't permit logging in as root. Allowed principals: ci"
+    );
+}
+
+#[test]
+fn rejects_a_truncated_or_garbage_certificate() {
+    let contents = std::fs::read_to_string("tests/fixtures/identity/ed25519-cert.pub").unwrap();
+    let mut parts = contents.split_whitespace();
+    let (key_type, encoded) = (parts.next().unwrap(), parts.next().unwrap());
+
+    // Cut the certificate off at every length, so each field is truncated in turn
+    let blob = STANDARD.decode(encoded).unwrap();
+    for len in 0..blob.len() {
+        let truncated = format!("{} {}", key_type, STANDARD.encode(&blob[..len]));
+        assert!(Certificate::parse(&truncated).is_err(), "{} bytes", len);
+    }
+
+    // A length prefix far beyond the end of the certificate
+    let mut oversized = blob.clone();
+    oversized[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+    let oversized = format!("{} {}", key_type, STANDARD.encode(&oversized));
+
+    for garbage in [
+        "",
+        "garbage",
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA== bot",
+        "ssh-ed25519-cert-v01@openssh.com !!!not-base64!!!",
+        "ssh-ed25519-cert-v01@openssh.com AAAA",
+        &oversized,
+    ] {
+        assert!(Certificate::parse(garbage).is_err(), "{}", garbage);
+    }
+}
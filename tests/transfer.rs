@@ -0,0 +1,540 @@
+mod common;
+
+use common::{config, report, Recorder};
+use drone_teleport::{
+    config::{rollout::Cancellation, state::SubCommand},
+    transport::{LocalConnector, LocalTransport},
+    TransferEngine,
+};
+use std::{collections::HashMap, sync::Arc};
+
+// Runs the transfer operation against hosts faked by `connector`, returning the exit code
+async fn transfer(connector: LocalConnector, args: &[&str], compress: bool) -> i32 {
+    let mut cfg = config(args);
+    let mut transfer = match &cfg.cmd {
+        SubCommand::Transfer(transfer) => transfer.to_owned(),
+        _ => unreachable!(),
+    };
+
+    // compress is a flag that is only turned off through PLUGIN_COMPRESS
+    transfer.compress = compress;
+    cfg.cmd = SubCommand::Transfer(transfer.to_owned());
+    transfer
+        .transfer_with(&cfg, Arc::new(connector))
+        .await
+        .unwrap_or_else(|error| error.exit_code())
+}
+
+// The value of settings:files, transferring the fixture site to `dst`
+fn files(dst: &str) -> String {
+    serde_json::json!([{ "src": "tests/fixtures/site/**/*", "dst": dst }]).to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_files_to_every_host() {
+    for compress in [true, false] {
+        let root = tempfile::tempdir().unwrap();
+        let report_path = root.path().join("report.json");
+        let files = files("app/releases/1");
+
+        let code = transfer(
+            LocalConnector::new(root.path()),
+            &[
+                "--hosts",
+                "web-1,web-2",
+                "--report-path",
+                report_path.to_str().unwrap(),
+                "transfer",
+                "--files",
+                &files,
+            ],
+            compress,
+        )
+        .await;
+
+        assert_eq!(code, 0);
+        for host in ["web-1", "web-2"] {
+            let release = root.path().join(host).join("app/releases/1");
+            assert_eq!(
+                std::fs::read_to_string(release.join("tests/fixtures/site/index.html")).unwrap(),
+                "<h1>drone-teleport</h1>\n"
+            );
+            assert!(release.join("tests/fixtures/site/assets/app.css").exists());
+
+            // The uploaded archive is removed once it was extracted
+            let leftovers: Vec<_> = std::fs::read_dir(&release)
+                .unwrap()
+                .flatten()
+                .filter(|entry| entry.file_name().to_string_lossy().contains(".tar"))
+                .collect();
+            assert!(leftovers.is_empty());
+        }
+
+        let report = report(&report_path);
+        let step = &report["hosts"][0]["steps"][0];
+        assert_eq!(report["operation"], "transfer");
+        assert_eq!(report["hosts"][0]["completed"], 1);
+        assert_eq!(step["name"], "tests/fixtures/site/**/*");
+        assert!(step["bytes"].as_u64().unwrap() > 0);
+        assert!(step["size"].as_u64().unwrap() > 0);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_when_the_archive_cannot_be_uploaded() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let files = files("blocked");
+
+    // A file is in the way of the destination directory
+    std::fs::create_dir_all(root.path().join("web-1")).unwrap();
+    std::fs::write(root.path().join("web-1/blocked"), "").unwrap();
+
+    for stream in [None, Some("--stream")] {
+        let mut args = vec![
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "transfer",
+            "--files",
+            &files,
+        ];
+        args.extend(stream);
+
+        let code = transfer(LocalConnector::new(root.path()), &args, true).await;
+
+        assert_eq!(code, 1);
+        let report = report(&report_path);
+        let failure = &report["hosts"][0]["failure"];
+        assert_eq!(report["hosts"][0]["status"], "failed");
+        assert_eq!(failure["name"], "tests/fixtures/site/**/*");
+        assert!(failure["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("unable to create remote file"));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_on_a_file_without_dst() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let files = serde_json::json!([
+        { "src": "tests/fixtures/site/*", "dst": "app" },
+        { "src": "tests/fixtures/site/**/*" },
+    ])
+    .to_string();
+
+    let code = transfer(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "transfer",
+            "--files",
+            &files,
+        ],
+        true,
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    assert!(!root.path().join("web-1").exists());
+    assert!(report(&report_path)["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid settings:files: entry 1 has no dst"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deletes_the_partial_archive_when_the_upload_fails() {
+    for stream in [None, Some("--stream")] {
+        let root = tempfile::tempdir().unwrap();
+        let files = files("app");
+        let mut connector = LocalConnector::new(root.path());
+        connector.failing_uploads("web-1");
+
+        let mut args = vec!["--hosts", "web-1", "transfer", "--files", &files];
+        args.extend(stream);
+
+        assert_eq!(transfer(connector, &args, true).await, 1);
+
+        // The archive was created on the host before the upload failed, and is gone again
+        let left: Vec<_> = std::fs::read_dir(root.path().join("web-1/app"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert!(left.is_empty(), "{:?}", left);
+    }
+}
+
+#[test]
+fn plans_a_transfer_without_touching_the_host() {
+    let mut engine = TransferEngine::new();
+    let plan = engine.plan("tests/fixtures/site/**/*").unwrap();
+
+    let index = plan
+        .files
+        .iter()
+        .find(|(path, _)| path.ends_with("index.html"))
+        .unwrap();
+    assert_eq!(index.1, 24);
+    assert_eq!(plan.files.len(), 3);
+    assert!(plan.upload.ends_with(".tar.zst"));
+    assert_eq!(
+        plan.remote_path("app"),
+        format!("app/{}", plan.archive_name)
+    );
+    assert_eq!(
+        plan.extract("app"),
+        format!("tar -xf app/{} -C app", plan.archive_name)
+    );
+
+    let plan = engine
+        .compress(false)
+        .plan("tests/fixtures/site/**/*")
+        .unwrap();
+    assert_eq!(plan.upload, plan.local_archive);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shares_one_archive_between_hosts() {
+    let root = tempfile::tempdir().unwrap();
+    let src = String::from("tests/fixtures/site/**/*");
+    let files = HashMap::from([(src.clone(), String::from("app"))]);
+    let engine = TransferEngine::new();
+    let recorder = Recorder::default();
+
+    let archives = engine.prepare_all([&src], &recorder);
+    let archive = archives[&src].as_ref().unwrap().clone();
+    let path = archive.path().to_string();
+    assert!(std::path::Path::new(&path).exists());
+
+    for host in ["web-1", "web-2"] {
+        let host_root = root.path().join(host);
+        std::fs::create_dir(&host_root).unwrap();
+        let transport = LocalTransport::new(&host_root);
+        let mut steps = Vec::new();
+        engine
+            .transfer(
+                &transport,
+                host,
+                &files,
+                &archives,
+                &Cancellation::default(),
+                &recorder,
+                &mut steps,
+            )
+            .await
+            .unwrap();
+        assert_eq!(steps[0].size, archive.size);
+        assert!(root
+            .path()
+            .join(host)
+            .join("app/tests/fixtures/site/index.html")
+            .exists());
+    }
+
+    // The archive is built once, and deleted once nothing uses it anymore
+    let archived: Vec<String> = recorder
+        .lines()
+        .into_iter()
+        .filter(|line| line.contains("Archiving"))
+        .collect();
+    assert_eq!(archived.len(), 1);
+    drop(archive);
+    drop(archives);
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_archives_without_temporary_files() {
+    // Large enough to take many chunks, and to fill the channel between archiving and the upload
+    let local = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let relative = local
+        .path()
+        .strip_prefix(std::env::current_dir().unwrap())
+        .unwrap();
+    let mut seed: u32 = 7;
+    let bundle: Vec<u8> = (0..5_000_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    std::fs::write(local.path().join("bundle.bin"), &bundle).unwrap();
+    let src = format!("{}/*", relative.display());
+
+    for compress in [true, false] {
+        let root = tempfile::tempdir().unwrap();
+        let files = HashMap::from([(src.clone(), String::from("app"))]);
+        let mut engine = TransferEngine::new();
+        engine.compress(compress).stream(true);
+
+        let mut steps = Vec::new();
+        let transport = LocalTransport::new(root.path());
+        engine
+            .transfer(
+                &transport,
+                "web-1",
+                &files,
+                &HashMap::new(),
+                &Cancellation::default(),
+                &drone_teleport::Silent,
+                &mut steps,
+            )
+            .await
+            .unwrap();
+
+        let extracted = root.path().join("app").join(relative).join("bundle.bin");
+        assert_eq!(std::fs::read(extracted).unwrap(), bundle);
+        assert!(steps[0].size.unwrap() > bundle.len() as u64);
+        assert!(steps[0].bytes.unwrap() > 0);
+
+        // Only the extracted files are left on the host
+        let entries: Vec<_> = std::fs::read_dir(root.path().join("app"))
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().contains(".tar"))
+            .collect();
+        assert!(entries.is_empty());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deploys_releases_and_switches_to_the_newest() {
+    let root = tempfile::tempdir().unwrap();
+    let app = root.path().join("web-1/app");
+    let files = files("app");
+
+    for release in ["r1", "r2", "r3"] {
+        let code = transfer(
+            LocalConnector::new(root.path()),
+            &[
+                "--hosts",
+                "web-1",
+                "transfer",
+                "--files",
+                &files,
+                "--atomic",
+                "--release",
+                release,
+                "--shared",
+                "storage,.env",
+                "--keep-releases",
+                "2",
+            ],
+            true,
+        )
+        .await;
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            std::fs::read_link(app.join("current")).unwrap(),
+            std::path::Path::new("releases").join(release)
+        );
+
+        // Files written by the app live in the current release, and are carried over to the next one
+        if release == "r1" {
+            std::fs::create_dir_all(app.join("current/storage")).unwrap();
+            std::fs::write(app.join("current/storage/data"), "kept").unwrap();
+        }
+    }
+
+    let current = app.join("current");
+    assert_eq!(
+        std::fs::read_to_string(current.join("tests/fixtures/site/index.html")).unwrap(),
+        "<h1>drone-teleport</h1>\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(current.join("storage/data")).unwrap(),
+        "kept"
+    );
+    assert!(!current.join(".env").exists());
+
+    // Only the newest releases are kept
+    let mut releases: Vec<String> = std::fs::read_dir(app.join("releases"))
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    releases.sort();
+    assert_eq!(releases, ["r2", "r3"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_the_current_release_when_a_deploy_fails() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let app = root.path().join("web-1/app");
+    let deploy = |files: String, release: &'static str| {
+        let root = root.path().to_owned();
+        let report_path = report_path.to_str().unwrap().to_owned();
+        async move {
+            transfer(
+                LocalConnector::new(&root),
+                &[
+                    "--hosts",
+                    "web-1",
+                    "--report-path",
+                    &report_path,
+                    "transfer",
+                    "--files",
+                    &files,
+                    "--atomic",
+                    "--release",
+                    release,
+                ],
+                true,
+            )
+            .await
+        }
+    };
+
+    assert_eq!(deploy(files("app"), "r1").await, 0);
+
+    // The current release is never deployed over
+    assert_eq!(deploy(files("app"), "r1").await, 1);
+    let failure = &report(&report_path)["hosts"][0]["failure"];
+    assert_eq!(failure["name"], "app");
+    assert!(failure["error"]
+        .as_str()
+        .unwrap()
+        .ends_with("release r1 is the current release"));
+    assert!(app.join("current/tests/fixtures/site/index.html").exists());
+
+    // One of the files can't be uploaded, so nothing is switched and the new release is deleted
+    std::fs::write(root.path().join("web-1/blocked"), "").unwrap();
+    let files = serde_json::json!([
+        { "src": "tests/fixtures/site/*", "dst": "app" },
+        { "src": "tests/fixtures/site/**/*", "dst": "blocked" },
+    ])
+    .to_string();
+    assert_eq!(deploy(files, "r2").await, 1);
+    assert_eq!(
+        std::fs::read_link(app.join("current")).unwrap(),
+        std::path::Path::new("releases/r1")
+    );
+    assert!(!app.join("releases/r2").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn switches_back_every_dst_when_one_cannot_be_switched() {
+    let root = tempfile::tempdir().unwrap();
+    let host = root.path().join("web-1");
+    // Each dst gets files of its own, as every src may only be listed once
+    let srcs = [
+        "tests/fixtures/site/index.html",
+        "tests/fixtures/site/assets/*",
+        "tests/fixtures/site/*",
+    ];
+    let deploy = |dsts: &[&str], release: &str| {
+        let files: Vec<_> = dsts
+            .iter()
+            .zip(srcs)
+            .map(|(dst, src)| serde_json::json!({ "src": src, "dst": dst }))
+            .collect();
+        let files = serde_json::Value::from(files).to_string();
+        let args = [
+            "--hosts",
+            "web-1",
+            "transfer",
+            "--files",
+            &files,
+            "--atomic",
+            "--release",
+            release,
+        ]
+        .map(String::from);
+        let root = root.path().to_owned();
+        async move {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            transfer(LocalConnector::new(&root), &args, true).await
+        }
+    };
+
+    assert_eq!(deploy(&["api"], "r1").await, 0);
+
+    // `current` of the last dst is a real directory, which can't be switched. Every dst is switched in order, so
+    // api and app already run r2 by then, and are switched back.
+    std::fs::create_dir_all(host.join("web/current/public")).unwrap();
+    assert_eq!(deploy(&["api", "app", "web"], "r2").await, 1);
+
+    assert_eq!(
+        std::fs::read_link(host.join("api/current")).unwrap(),
+        std::path::Path::new("releases/r1")
+    );
+    assert!(!host.join("api/releases/r2").exists());
+
+    // app had no release before, so it has none again
+    assert!(std::fs::symlink_metadata(host.join("app/current")).is_err());
+    assert!(!host.join("app/releases/r2").exists());
+    assert!(host.join("web/current/public").is_dir());
+}
+
+#[test]
+fn plans_a_release_without_touching_the_host() {
+    let cfg = config(&[
+        "--hosts",
+        "web-1",
+        "--dry-run",
+        "transfer",
+        "--files",
+        &files("app"),
+        "--atomic",
+        "--release",
+        "r1",
+    ]);
+    let transfer = match &cfg.cmd {
+        SubCommand::Transfer(transfer) => transfer.to_owned(),
+        _ => unreachable!(),
+    };
+
+    let engine = transfer.get_engine().unwrap();
+    let release = engine.get_release().unwrap();
+    assert_eq!(engine.destination("app"), "app/releases/r1");
+    assert_eq!(
+        release.switch("app"),
+        "ln -sfn releases/r1 app/.current-r1 && mv -Tf app/.current-r1 app/current"
+    );
+    assert!(release.share("app").is_none());
+
+    // Release names end up in shell commands, so only plain names are accepted
+    let mut invalid = transfer.clone();
+    invalid.release = Some(String::from("../r1"));
+    assert!(invalid.get_engine().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_absolute_paths_on_the_fake_host() {
+    let root = tempfile::tempdir().unwrap();
+    let report_path = root.path().join("report.json");
+    let dst = "/drone-teleport-fake/app";
+
+    // Commands on the fake host would extract into the real /drone-teleport-fake, so the upload is refused before
+    let code = transfer(
+        LocalConnector::new(root.path()),
+        &[
+            "--hosts",
+            "web-1",
+            "--report-path",
+            report_path.to_str().unwrap(),
+            "transfer",
+            "--files",
+            &files(dst),
+        ],
+        true,
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    assert!(report(&report_path)["hosts"][0]["failure"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("the fake host only accepts paths relative to its root"));
+    assert!(!std::path::Path::new(dst).exists());
+    assert!(!root.path().join("web-1/drone-teleport-fake").exists());
+}